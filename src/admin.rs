@@ -0,0 +1,479 @@
+use std::{collections::HashMap, fmt::Display};
+use actix_web::{get, post, delete, patch, HttpRequest, HttpResponse, web::{self, Bytes}, Result, http::StatusCode};
+use crate::{parse_seek_target, error_status, bearer_token, blocking};
+use crate::subjects::{event::parse_timestamp, topic::Topic, producer::Producer, consumer::Consumer, settings::{Settings, CleanupPolicy}, credential::{Credential, Role, Access}};
+use crate::configs::{topics::get_topics, producers::list_producers, consumers::list_consumers, credentials::list_credentials, errors::{InvalidToken, InvalidInput}};
+
+// Admin endpoints manage topics, producers & consumers the way sailfish-cli does on
+// the data directory. They answer with the same JSON the CLI prints and need a
+// credential token as a bearer token. Admins can do everything, owners can manage
+// their topic and auditors can read everything but the keys of other tokens.
+// Producers, consumers & credentials are addressed by ID, secrets only travel in the
+// Authorization header.
+//
+// Responses: 200 with the subject, 201 with a created subject, 400 for malformed
+// input, 401 for a missing or unknown credential, 403 when the credential's role
+// doesn't allow the request, 404 for unknown topics & IDs, 409 when a topic
+// already exists and 500 otherwise. Errors are always
+// { "success": false, "error": "..." }.
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+fn error_response(status: StatusCode, error: &str) -> HttpResponse {
+    return HttpResponse::build(status)
+            .content_type("application/json")
+            .body(format!("{{ \"success\": false, \"error\": \"{}\" }}", json_escape(error)));
+}
+
+fn admin_error_response(e: &anyhow::Error) -> HttpResponse {
+    // A token that matches no producer or consumer is just an unknown subject to an admin
+    if e.is::<InvalidToken>() {
+        return error_response(StatusCode::NOT_FOUND, "Unknown token.");
+    }
+    return error_response(error_status(e), &e.to_string());
+}
+
+// Credential of the request's bearer token
+async fn authenticate(req: &HttpRequest) -> Result<Credential, Box<HttpResponse>> {
+    let token = match bearer_token(req) {
+        Some(token) => token,
+        None => return Err(Box::new(error_response(StatusCode::UNAUTHORIZED, "Missing credential. Send it as Authorization: Bearer <token>."))),
+    };
+    match blocking(move || Credential::hydrate(&token)).await {
+        Ok(credential) => return Ok(credential),
+        Err(e) if e.is::<InvalidToken>() || e.is::<InvalidInput>() => return Err(Box::new(error_response(StatusCode::UNAUTHORIZED, "Invalid credential."))),
+        Err(e) => return Err(Box::new(error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))),
+    }
+}
+
+fn check(credential: &Credential, access: Access, topic: Option<&str>) -> Result<(), Box<HttpResponse>> {
+    if !credential.allows(access, topic) {
+        return Err(Box::new(error_response(StatusCode::FORBIDDEN, &format!("The {} role can't do that.", credential.role))));
+    }
+    return Ok(());
+}
+
+async fn authorize(req: &HttpRequest, access: Access, topic: Option<&str>) -> Result<Credential, Box<HttpResponse>> {
+    let credential = authenticate(req).await?;
+    check(&credential, access, topic)?;
+    return Ok(credential);
+}
+
+fn admin_response(result: anyhow::Result<String>) -> HttpResponse {
+    return admin_response_with(StatusCode::OK, result);
+}
+
+fn admin_response_with(status: StatusCode, result: anyhow::Result<String>) -> HttpResponse {
+    match result {
+        Ok(body) => return HttpResponse::build(status).content_type("application/json").body(body),
+        Err(e) => return admin_error_response(&e),
+    }
+}
+
+fn json_list<T: Display>(items: &[T]) -> String {
+    let items = items.iter().map(|item| item.to_string()).collect::<Vec<String>>();
+    return format!("[{}]", items.join(", "));
+}
+
+#[get("/admin/topics")]
+async fn list_topics_route(req: HttpRequest) -> Result<HttpResponse> {
+    let credential = match authenticate(&req).await {
+        Ok(credential) => credential,
+        Err(response) => return Ok(*response),
+    };
+    let topics = blocking(get_topics).await.map(|topics| {
+        return topics.into_iter()
+                .filter(|topic| credential.allows(Access::Read, Some(&topic.name)))
+                .collect::<Vec<Topic>>();
+    });
+    return Ok(admin_response(topics.map(|topics| json_list(&topics))));
+}
+
+#[post("/admin/topics")]
+async fn add_topic(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Write, None).await {
+        return Ok(*response);
+    }
+    let name = match query.get("name") {
+        Some(name) => name.to_lowercase(),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Missing topic.")),
+    };
+    return Ok(admin_response_with(StatusCode::CREATED, blocking(move || Topic::new(name)).await.map(|topic| topic.to_string())));
+}
+
+#[get("/admin/topics/{topic}")]
+async fn stat_topic(req: HttpRequest, topic: web::Path<String>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Read, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let topic = topic.into_inner();
+    let stats = blocking(move || Topic::hydrate(&topic)?.stats()).await;
+    return Ok(admin_response(stats.map(|stats| stats.to_string())));
+}
+
+// Owners can empty their topic but only admins remove topics
+#[delete("/admin/topics/{topic}")]
+async fn delete_topic(req: HttpRequest, topic: web::Path<String>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Write, None).await {
+        return Ok(*response);
+    }
+    let topic = topic.into_inner();
+    let result = blocking(move || Topic::hydrate(&topic)?.delete()).await;
+    return Ok(admin_response(result.map(|_| "{ \"success\": true }".to_string())));
+}
+
+fn cleanup_topic(topic: &str) -> anyhow::Result<String> {
+    let mut topic = Topic::hydrate(topic)?;
+    if topic.settings()?.cleanup_policy == Some(CleanupPolicy::Compact) {
+        let compaction = topic.compact()?;
+        let skipped:Vec<u64> = compaction.skipped.iter().map(|(log_file, _)| *log_file).collect();
+        return Ok(format!("{{ \"success\": true, \"compacted_events\": {}, \"skipped_log_files\": {:?} }}", compaction.dropped, skipped));
+    }
+    let deleted = topic.cleanup()?;
+    return Ok(format!("{{ \"success\": true, \"deleted_log_files\": {} }}", deleted));
+}
+
+#[post("/admin/topics/{topic}/cleanup")]
+async fn cleanup(req: HttpRequest, topic: web::Path<String>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Write, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let topic = topic.into_inner();
+    return Ok(admin_response(blocking(move || cleanup_topic(&topic)).await));
+}
+
+#[get("/admin/topics/{topic}/settings")]
+async fn get_settings(req: HttpRequest, topic: web::Path<String>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Read, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let topic = topic.into_inner();
+    let settings = blocking(move || Topic::hydrate(&topic)?.settings()).await;
+    return Ok(admin_response(settings.map(|settings| settings.to_string())));
+}
+
+// Applies key=value lines from the body, in order
+fn configure_topic(topic: &str, body: &str) -> anyhow::Result<Settings> {
+    let topic = Topic::hydrate(topic)?;
+    let mut settings = topic.settings()?;
+    for line in body.lines().filter(|line| !line.trim().is_empty()) {
+        let (key, value) = line.split_once('=').ok_or(InvalidInput { reason: "Settings must be formatted as key=value.".to_string() })?;
+        settings = topic.configure(key.trim(), value.trim())?;
+    }
+    return Ok(settings);
+}
+
+#[patch("/admin/topics/{topic}/settings")]
+async fn configure(req: HttpRequest, bytes: Bytes, topic: web::Path<String>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Write, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let body = match String::from_utf8(bytes.to_vec()) {
+        Ok(body) => body,
+        Err(_) => return Ok(error_response(StatusCode::BAD_REQUEST, "Settings must be UTF-8 text.")),
+    };
+    let topic = topic.into_inner();
+    return Ok(admin_response(blocking(move || configure_topic(&topic, &body)).await.map(|settings| settings.to_string())));
+}
+
+// ?expires= takes milliseconds since the Unix epoch or an RFC 3339 date time
+fn expires_at(query: &HashMap<String, String>) -> Result<Option<u64>, Box<HttpResponse>> {
+    match query.get("expires").map(|expires| parse_timestamp(expires)) {
+        Some(Ok(expires_at)) => return Ok(Some(expires_at)),
+        Some(Err(e)) => return Err(Box::new(error_response(StatusCode::BAD_REQUEST, &e.to_string()))),
+        None => return Ok(None),
+    }
+}
+
+// ?grace= is how many milliseconds a rerolled key keeps working
+fn grace(query: &HashMap<String, String>) -> Result<u64, Box<HttpResponse>> {
+    match query.get("grace").map(|grace| grace.parse::<u64>()) {
+        Some(Ok(grace_ms)) => return Ok(grace_ms),
+        Some(Err(_)) => return Err(Box::new(error_response(StatusCode::BAD_REQUEST, "Invalid grace period. Grace period must be a number of milliseconds."))),
+        None => return Ok(0),
+    }
+}
+
+// ?topic= limits the list to the producers granted the topic
+#[get("/admin/producers")]
+async fn list_producers_route(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let credential = match authenticate(&req).await {
+        Ok(credential) => credential,
+        Err(response) => return Ok(*response),
+    };
+    let producers = blocking(list_producers).await.map(|producers| {
+        return producers.into_iter()
+                .filter(|producer| query.get("topic").is_none_or(|topic| producer.grants(topic)))
+                .filter(|producer| credential.allows(Access::Read, Some(&producer.topic)))
+                .collect::<Vec<Producer>>();
+    });
+    return Ok(admin_response(producers.map(|producers| json_list(&producers))));
+}
+
+#[post("/admin/producers")]
+async fn add_producer(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let topic = match query.get("topic") {
+        Some(topic) => topic.to_lowercase(),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Missing topic.")),
+    };
+    if let Err(response) = authorize(&req, Access::Write, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let expires_at = match expires_at(&query) {
+        Ok(expires_at) => expires_at,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response_with(StatusCode::CREATED, blocking(move || Producer::new(topic, expires_at)).await.map(|producer| producer.to_string())));
+}
+
+// Producers, consumers & credentials are addressed by ID, the offset they are listed
+// with. Tokens are refused so their secrets stay out of URLs & access logs.
+fn record_id(id: &str) -> Result<u64, Box<HttpResponse>> {
+    if id.contains('-') {
+        return Err(Box::new(error_response(StatusCode::BAD_REQUEST, "Address producers, consumers and credentials by ID, not by token.")));
+    }
+    return id.parse::<u64>().map_err(|_| Box::new(error_response(StatusCode::BAD_REQUEST, "Invalid ID.")));
+}
+
+// The producer with an ID, when the credential may access its topic
+async fn authorize_producer(req: &HttpRequest, access: Access, id: &str) -> Result<(Credential, Producer), Box<HttpResponse>> {
+    let credential = authenticate(req).await?;
+    let id = record_id(id)?;
+    let producer = blocking(move || Producer::find(id)).await.map_err(|e| Box::new(admin_error_response(&e)))?;
+    check(&credential, access, Some(&producer.topic))?;
+    return Ok((credential, producer));
+}
+
+#[get("/admin/producers/{id}")]
+async fn stat_producer(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, producer) = match authorize_producer(&req, Access::Read, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(Ok(producer.to_string())));
+}
+
+#[delete("/admin/producers/{id}")]
+async fn delete_producer(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, producer) = match authorize_producer(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(blocking(move || producer.delete()).await.map(|_| "{ \"success\": true }".to_string())));
+}
+
+// ?grace= keeps the replaced key working, ?expires= sets when the new key expires
+#[post("/admin/producers/{id}/reroll")]
+async fn reroll_producer(req: HttpRequest, id: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let (_, mut producer) = match authorize_producer(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    let (grace_ms, expires_at) = match (grace(&query), expires_at(&query)) {
+        (Ok(grace_ms), Ok(expires_at)) => (grace_ms, expires_at),
+        (Err(response), _) | (_, Err(response)) => return Ok(*response),
+    };
+    let result = blocking(move || {
+        producer.reroll(grace_ms, expires_at)?;
+        return Ok(producer.to_string());
+    });
+    return Ok(admin_response(result.await));
+}
+
+// ?topic= limits the list to the consumers granted the topic
+#[get("/admin/consumers")]
+async fn list_consumers_route(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let credential = match authenticate(&req).await {
+        Ok(credential) => credential,
+        Err(response) => return Ok(*response),
+    };
+    let consumers = blocking(list_consumers).await.map(|consumers| {
+        return consumers.into_iter()
+                .filter(|consumer| query.get("topic").is_none_or(|topic| consumer.grants(topic)))
+                .filter(|consumer| credential.allows(Access::Read, Some(&consumer.topic)))
+                .collect::<Vec<Consumer>>();
+    });
+    return Ok(admin_response(consumers.map(|consumers| json_list(&consumers))));
+}
+
+#[post("/admin/consumers")]
+async fn add_consumer(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let topic = match query.get("topic") {
+        Some(topic) => topic.to_lowercase(),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Missing topic.")),
+    };
+    if let Err(response) = authorize(&req, Access::Write, Some(&topic)).await {
+        return Ok(*response);
+    }
+    let expires_at = match expires_at(&query) {
+        Ok(expires_at) => expires_at,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response_with(StatusCode::CREATED, blocking(move || Consumer::new(topic, expires_at)).await.map(|consumer| consumer.to_string())));
+}
+
+// The consumer with an ID, when the credential may access its topic
+async fn authorize_consumer(req: &HttpRequest, access: Access, id: &str) -> Result<(Credential, Consumer), Box<HttpResponse>> {
+    let credential = authenticate(req).await?;
+    let id = record_id(id)?;
+    let consumer = blocking(move || Consumer::find(id)).await.map_err(|e| Box::new(admin_error_response(&e)))?;
+    check(&credential, access, Some(&consumer.topic))?;
+    return Ok((credential, consumer));
+}
+
+#[get("/admin/consumers/{id}")]
+async fn stat_consumer(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, consumer) = match authorize_consumer(&req, Access::Read, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(Ok(consumer.to_string())));
+}
+
+#[delete("/admin/consumers/{id}")]
+async fn delete_consumer(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, consumer) = match authorize_consumer(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(blocking(move || consumer.delete()).await.map(|_| "{ \"success\": true }".to_string())));
+}
+
+// ?grace= keeps the replaced key working, ?expires= sets when the new key expires
+#[post("/admin/consumers/{id}/reroll")]
+async fn reroll_consumer(req: HttpRequest, id: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let (_, mut consumer) = match authorize_consumer(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    let (grace_ms, expires_at) = match (grace(&query), expires_at(&query)) {
+        (Ok(grace_ms), Ok(expires_at)) => (grace_ms, expires_at),
+        (Err(response), _) | (_, Err(response)) => return Ok(*response),
+    };
+    let result = blocking(move || {
+        consumer.reroll(grace_ms, expires_at)?;
+        return Ok(consumer.to_string());
+    });
+    return Ok(admin_response(result.await));
+}
+
+#[post("/admin/consumers/{id}/seek")]
+async fn seek_consumer(req: HttpRequest, id: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let (_, consumer) = match authorize_consumer(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    let target = match parse_seek_target(&query) {
+        Ok(target) => target,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    // Consumers of several topics seek in the topic given by ?topic=
+    let topic = query.get("topic").cloned();
+    let result = blocking(move || {
+        let mut consumer = consumer.for_topic(topic.as_deref())?;
+        consumer.seek(&target)?;
+        return Ok(consumer.to_string());
+    });
+    return Ok(admin_response(result.await));
+}
+
+#[get("/admin/credentials")]
+async fn list_credentials_route(req: HttpRequest) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Read, None).await {
+        return Ok(*response);
+    }
+    return Ok(admin_response(blocking(list_credentials).await.map(|credentials| json_list(&credentials))));
+}
+
+// ?role=admin|owner|auditor, owners also need ?topic=
+#[post("/admin/credentials")]
+async fn add_credential(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, Access::Write, None).await {
+        return Ok(*response);
+    }
+    let role = match query.get("role").map(|role| role.parse::<Role>()) {
+        Some(Ok(role)) => role,
+        Some(Err(e)) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Missing role.")),
+    };
+    let topic = query.get("topic").cloned();
+    let credential = blocking(move || Credential::new(role, topic)).await;
+    return Ok(admin_response_with(StatusCode::CREATED, credential.map(|credential| credential.to_string())));
+}
+
+// The credential with an ID, when the requesting credential may manage credentials
+async fn authorize_credential(req: &HttpRequest, access: Access, id: &str) -> Result<(Credential, Credential), Box<HttpResponse>> {
+    let credential = authorize(req, access, None).await?;
+    let id = record_id(id)?;
+    let other = blocking(move || Credential::find(id)).await.map_err(|e| Box::new(admin_error_response(&e)))?;
+    return Ok((credential, other));
+}
+
+#[get("/admin/credentials/{id}")]
+async fn stat_credential(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, other) = match authorize_credential(&req, Access::Read, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(Ok(other.to_string())));
+}
+
+#[delete("/admin/credentials/{id}")]
+async fn delete_credential(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, other) = match authorize_credential(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    return Ok(admin_response(blocking(move || other.delete()).await.map(|_| "{ \"success\": true }".to_string())));
+}
+
+#[post("/admin/credentials/{id}/reroll")]
+async fn reroll_credential(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse> {
+    let (_, mut other) = match authorize_credential(&req, Access::Write, &id).await {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(*response),
+    };
+    let result = blocking(move || {
+        other.reroll()?;
+        return Ok(other.to_string());
+    });
+    return Ok(admin_response(result.await));
+}
+
+// Registered ahead of the path-token routes, which would otherwise match /admin/...
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_topics_route)
+        .service(add_topic)
+        .service(stat_topic)
+        .service(delete_topic)
+        .service(cleanup)
+        .service(get_settings)
+        .service(configure)
+        .service(list_producers_route)
+        .service(add_producer)
+        .service(stat_producer)
+        .service(delete_producer)
+        .service(reroll_producer)
+        .service(list_consumers_route)
+        .service(add_consumer)
+        .service(stat_consumer)
+        .service(delete_consumer)
+        .service(reroll_consumer)
+        .service(seek_consumer)
+        .service(list_credentials_route)
+        .service(add_credential)
+        .service(stat_credential)
+        .service(delete_credential)
+        .service(reroll_credential);
+}
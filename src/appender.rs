@@ -0,0 +1,77 @@
+use std::{collections::{HashMap, HashSet}, sync::{Mutex, mpsc::{self, Sender, Receiver}}, thread};
+use anyhow::{Result, anyhow};
+use crate::configs::topics::{write, sync_topic_file};
+use crate::subjects::event::Event;
+
+// Events are appended to a log by one thread per topic, so records never interleave
+// and only one writer decides when to roll over to the next log file.
+// Writes that are already queued when the thread picks up work are appended back to
+// back and share a single fsync.
+#[derive(Debug, Default)]
+pub struct Appenders {
+    topics: Mutex<HashMap<String, Sender<Append>>>,
+}
+
+struct Append {
+    event: Event,
+    sync: bool,
+    // Receives the log file the event was appended to
+    reply: Sender<Result<u64>>,
+}
+
+impl Appenders {
+    pub fn new() -> Self {
+        return Appenders::default();
+    }
+
+    // Blocks until the event is appended, and synced to disk when asked to
+    pub fn append(&self, topic: &str, event: Event, sync: bool) -> Result<u64> {
+        let (reply, response) = mpsc::channel();
+        self.sender(topic).send(Append { event, sync, reply }).map_err(|_| anyhow!("Appender of topic {} has stopped.", topic))?;
+        return response.recv().map_err(|_| anyhow!("Appender of topic {} has stopped.", topic))?;
+    }
+
+    fn sender(&self, topic: &str) -> Sender<Append> {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(sender) = topics.get(topic) {
+            return sender.clone();
+        }
+        let (sender, receiver) = mpsc::channel();
+        let name = topic.to_owned();
+        thread::spawn(move || run_appender(&name, receiver));
+        topics.insert(topic.to_owned(), sender.clone());
+        return sender;
+    }
+}
+
+fn run_appender(topic: &str, receiver: Receiver<Append>) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        batch.extend(receiver.try_iter());
+
+        let mut results:Vec<Result<u64>> = Vec::with_capacity(batch.len());
+        let mut unsynced:HashSet<u64> = HashSet::new();
+        for append in batch.iter() {
+            let result = write(topic, &append.event, false);
+            if let (true, Ok(log_file)) = (append.sync, &result) {
+                unsynced.insert(*log_file);
+            }
+            results.push(result);
+        }
+
+        let mut sync_error:Option<String> = None;
+        for log_file in unsynced {
+            if let Err(e) = sync_topic_file(topic, &log_file) {
+                sync_error = Some(format!("Failed to sync log file {} of topic {} to disk: {}", log_file, topic, e));
+            }
+        }
+
+        for (append, result) in batch.into_iter().zip(results) {
+            let result = match (&sync_error, append.sync) {
+                (Some(error), true) if result.is_ok() => Err(anyhow!("{}", error)),
+                _ => result,
+            };
+            let _ = append.reply.send(result);
+        }
+    }
+}
@@ -1,14 +1,24 @@
+#![allow(clippy::needless_return)]
+
 mod subjects;
 mod configs;
+mod remote;
 
 use std::str::FromStr;
 use std::env;
+use configs::{init_data_dir, get_flag};
 use configs::consumers::list_consumers;
 use configs::producers::list_producers;
-use configs::topics::list_topics;
-use subjects::consumer::Consumer;
+use configs::topics::get_topics;
+use configs::credentials::list_credentials;
+use configs::keys::hash_plain_keys;
+use subjects::consumer::{Consumer, SeekTarget};
 use subjects::producer::Producer;
 use subjects::topic::Topic;
+use subjects::credential::{Credential, Role};
+use subjects::settings::{Durability, CleanupPolicy};
+use subjects::event::{Event, parse_timestamp};
+use remote::Remote;
 use anyhow::Result;
 
 enum Commands {
@@ -20,12 +30,16 @@ enum Commands {
     List,
     Write,
     Read,
+    Config,
+    Seek,
+    Migrate,
 }
 
 enum Subject {
     Producer,
     Consumer,
     Topic,
+    Credential,
 }
 
 impl FromStr for Commands {
@@ -43,6 +57,9 @@ impl FromStr for Commands {
             "list" => return Ok(Commands::List),
             "write" => return Ok(Commands::Write),
             "read" => return  Ok(Commands::Read),
+            "config" => return Ok(Commands::Config),
+            "seek" => return Ok(Commands::Seek),
+            "migrate" => return Ok(Commands::Migrate),
             _ => Err("Invalid command.".to_string()),
         }    
     }
@@ -59,13 +76,34 @@ impl FromStr for Subject {
             "producers" => return Ok(Subject::Producer),
             "consumers" => return Ok(Subject::Consumer),
             "topics" => return Ok(Subject::Topic),
+            "credential" => return Ok(Subject::Credential),
+            "credentials" => return Ok(Subject::Credential),
             _ => Err("Invalid command subject.".to_string()),
         }    
     }
 }
 
+// Flags that apply to every command & take a value, they can appear anywhere
+const GLOBAL_FLAGS:[&str; 4] = ["--data-dir", "--config", "--server", "--admin-token"];
+
+// Command line arguments without the global flags, so commands can keep reading
+// their arguments by position
+fn args() -> std::vec::IntoIter<String> {
+    let mut args:Vec<String> = Vec::new();
+    let mut all_args = env::args();
+    while let Some(arg) = all_args.next() {
+        if GLOBAL_FLAGS.contains(&arg.as_str()) {
+            all_args.next();
+            continue;
+        }
+        args.push(arg);
+    }
+    return args.into_iter();
+}
+
 fn main(){
-    let cmd = env::args()
+    let all_args = env::args().collect::<Vec<String>>();
+    let cmd = args()
                 .nth(1)
                 .unwrap_or_else(|| {
                     output_error("Missing command.");
@@ -77,6 +115,25 @@ fn main(){
                     output_error(&e);
                     std::process::exit(1);
                 });
+
+    // With a server, commands go through its API instead of the data directory
+    let server = get_flag(&all_args, "--server").unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    }).or(env::var("SAILFISH_SERVER").ok());
+    if let Some(server) = server {
+        let admin_token = get_flag(&all_args, "--admin-token").unwrap_or_else(|e| {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        }).or(env::var("SAILFISH_ADMIN_TOKEN").ok());
+        Remote::new(server, admin_token).run(cmd);
+        return;
+    }
+
+    init_data_dir(&all_args).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
     match cmd {
         Commands::Cleanup => cleanup(),
         Commands::Add => add(),
@@ -86,6 +143,9 @@ fn main(){
         Commands::List => list_subject(),
         Commands::Write => write(),
         Commands::Read => read(),
+        Commands::Config => config(),
+        Commands::Seek => seek(),
+        Commands::Migrate => migrate(),
     }
 }
 
@@ -94,7 +154,7 @@ fn output_error(e: &str){
 }
 
 fn get_subject() -> Subject {
-    let subject = env::args()
+    let subject = args()
                     .nth(2)
                     .unwrap_or_else(|| {
                         output_error("Missing command subject.");
@@ -110,7 +170,7 @@ fn get_subject() -> Subject {
 }
 
 fn get_topic() -> String {
-    let topic = env::args()
+    let topic = args()
                     .nth(3)
                     .unwrap_or_else(|| {
                         output_error("Missing topic.");
@@ -120,19 +180,71 @@ fn get_topic() -> String {
     return topic;
 }
 
-fn get_token() -> String {
-    let token = env::args()
-                    .nth(3)
-                    .unwrap_or_else(|| {
-                        output_error("Missing token.");
-                        std::process::exit(1);
-                    })
-                    .to_lowercase();
-    return token;
+// Producers, consumers & credentials are addressed by ID, the offset they are listed
+// with. A token works too, only the ID in front of its key is used.
+fn get_id() -> u64 {
+    let id = args()
+                .nth(3)
+                .unwrap_or_else(|| {
+                    output_error("Missing ID.");
+                    std::process::exit(1);
+                });
+    let id = id.split_once('-').map(|(id, _)| id.to_owned()).unwrap_or(id);
+    return id.parse::<u64>().unwrap_or_else(|_| {
+        output_error("Invalid ID. IDs are the offsets producers, consumers and credentials are listed with.");
+        std::process::exit(1);
+    });
+}
+
+// Value following any of the given flags, e.g. --offset 10
+fn get_option(flags: &[&str]) -> Option<String> {
+    let args = args().collect::<Vec<String>>();
+    let i = args.iter().position(|arg| flags.contains(&arg.as_str()))?;
+    return args.get(i+1).cloned();
+}
+
+// --expires takes milliseconds since the Unix epoch or an RFC 3339 date time
+fn get_expires_at() -> Option<u64> {
+    let expires_at = get_option(&["--expires"])?;
+    let expires_at = parse_timestamp(&expires_at).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    return Some(expires_at);
+}
+
+// --grace is how many milliseconds a rerolled key keeps working
+fn get_grace() -> u64 {
+    let grace = match get_option(&["--grace"]) {
+        Some(grace) => grace,
+        None => return 0,
+    };
+    return grace.parse::<u64>().unwrap_or_else(|_| {
+        output_error("Invalid grace period. Grace period must be a number of milliseconds.");
+        std::process::exit(1);
+    });
+}
+
+fn get_offset() -> Option<u64> {
+    let offset = get_option(&["-o", "--offset"])?;
+    let offset = offset.parse::<u64>().unwrap_or_else(|_| {
+        output_error("Invalid offset.");
+        std::process::exit(1);
+    });
+    return Some(offset);
+}
+
+// Hashes the keys of registries written before keys were hashed
+fn migrate(){
+    let hashed = hash_plain_keys().unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{{ \"success\": true, \"hashed_keys\": {} }}", hashed);
 }
 
 fn cleanup(){
-    let topic = env::args()
+    let topic = args()
                     .nth(2)
                     .unwrap_or_else(|| {
                         output_error("Missing topic.");
@@ -143,11 +255,24 @@ fn cleanup(){
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    topic.cleanup().unwrap_or_else(|e| {
+    let settings = topic.settings().unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    if settings.cleanup_policy == Some(CleanupPolicy::Compact) {
+        let compaction = topic.compact().unwrap_or_else(|e| {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        });
+        let skipped:Vec<u64> = compaction.skipped.iter().map(|(log_file, _)| *log_file).collect();
+        println!("{{ \"success\": true, \"compacted_events\": {}, \"skipped_log_files\": {:?} }}", compaction.dropped, skipped);
+        return;
+    }
+    let deleted = topic.cleanup().unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    println!("{{ \"success\": true }}");
+    println!("{{ \"success\": true, \"deleted_log_files\": {} }}", deleted);
 }
 
 fn add() {
@@ -156,12 +281,13 @@ fn add() {
         Subject::Producer => add_producer(),
         Subject::Consumer => add_consumer(),
         Subject::Topic => add_topic(),
+        Subject::Credential => add_credential(),
     }
 }
 
 fn add_consumer() {
     let topic = get_topic();
-    let consumer = Consumer::new(topic).unwrap_or_else(|e| {
+    let consumer = Consumer::new(topic, get_expires_at()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -170,7 +296,7 @@ fn add_consumer() {
 
 fn add_producer(){
     let topic = get_topic();
-    let producer = Producer::new(topic).unwrap_or_else(|e| {
+    let producer = Producer::new(topic, get_expires_at()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -186,18 +312,55 @@ fn add_topic() {
     println!("{}", topic);
 }
 
+// add credential <admin|owner|auditor> [topic], owners need the topic they own
+fn get_role() -> (Role, Option<String>) {
+    let role = args()
+                    .nth(3)
+                    .unwrap_or_else(|| {
+                        output_error("Missing role.");
+                        std::process::exit(1);
+                    })
+                    .to_lowercase();
+    let role = role.parse::<Role>().unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    return (role, args().nth(4).map(|topic| topic.to_lowercase()));
+}
+
+fn add_credential() {
+    let (role, topic) = get_role();
+    let credential = Credential::new(role, topic).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{}", credential);
+}
+
 fn delete() {
     let subject = get_subject();
     match subject {
         Subject::Producer => delete_producer(),
         Subject::Consumer => delete_consumer(),
         Subject::Topic => delete_topic(),
+        Subject::Credential => delete_credential(),
     }
 }
 
+fn delete_credential(){
+    let credential = Credential::find(get_id()).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    credential.delete().unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{{ \"success\": true  }}");
+}
+
 fn delete_producer(){
-    let token = get_token();
-    let producer = Producer::hydrate(&token).unwrap_or_else(|e| {
+    let producer = Producer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -209,8 +372,7 @@ fn delete_producer(){
 }
 
 fn delete_consumer(){
-    let token = get_token();
-    let consumer = Consumer::hydrate(&token).unwrap_or_else(|e| {
+    let consumer = Consumer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -239,6 +401,7 @@ fn reroll() {
     match subject {
         Subject::Producer => reroll_producer(),
         Subject::Consumer => reroll_consumer(),
+        Subject::Credential => reroll_credential(),
         Subject::Topic => {
             output_error("Topics cannot be rerolled.");
             std::process::exit(1);
@@ -246,13 +409,24 @@ fn reroll() {
     }
 }
 
+fn reroll_credential(){
+    let mut credential = Credential::find(get_id()).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    credential.reroll().unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{}", credential);
+}
+
 fn reroll_producer(){
-    let token = get_token();
-    let mut producer = Producer::hydrate(&token).unwrap_or_else(|e| {
+    let mut producer = Producer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    producer.reroll().unwrap_or_else(|e| {
+    producer.reroll(get_grace(), get_expires_at()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -260,12 +434,11 @@ fn reroll_producer(){
 }
 
 fn reroll_consumer(){
-    let token = get_token();
-    let mut consumer = Consumer::hydrate(&token).unwrap_or_else(|e| {
+    let mut consumer = Consumer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    consumer.reroll().unwrap_or_else(|e| {
+    consumer.reroll(get_grace(), get_expires_at()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -278,12 +451,20 @@ fn stat_subject() {
         Subject::Producer => stat_producer(),
         Subject::Consumer => stat_consumer(),
         Subject::Topic => stat_topic(),
+        Subject::Credential => stat_credential(),
     }
 }
 
+fn stat_credential(){
+    let credential = Credential::find(get_id()).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{}", credential);
+}
+
 fn stat_producer(){
-    let token = get_token();
-    let producer = Producer::hydrate(&token).unwrap_or_else(|e| {
+    let producer = Producer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -291,8 +472,7 @@ fn stat_producer(){
 }
 
 fn stat_consumer(){
-    let token = get_token();
-    let consumer = Consumer::hydrate(&token).unwrap_or_else(|e| {
+    let consumer = Consumer::find(get_id()).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -301,55 +481,171 @@ fn stat_consumer(){
 
 fn stat_topic(){
     let topic = get_topic();
-    let topic = Topic::hydrate(&topic).unwrap_or_else(|e|{
+    let stats = Topic::hydrate(&topic).and_then(|topic| topic.stats()).unwrap_or_else(|e|{
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    println!("{}", topic);
+    println!("{}", stats);
 }
 
-fn list_subject(){
+fn seek(){
     let subject = get_subject();
     match subject {
-        Subject::Producer => list_producers().unwrap_or_else(|e|{
-            output_error(&e.to_string());
+        Subject::Consumer => seek_consumer(),
+        _ => {
+            output_error("Only consumers can seek.");
             std::process::exit(1);
-        }),
-        Subject::Consumer => list_consumers().unwrap_or_else(|e|{
+        },
+    }
+}
+
+fn seek_consumer(){
+    let mut consumer = Consumer::find(get_id()).and_then(|consumer| consumer.for_topic(get_data_topic().as_deref())).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    let args = args().collect::<Vec<String>>();
+    let target = if let Some(offset) = get_offset() {
+        SeekTarget::Offset(offset)
+    } else if let Some(time) = get_option(&["-t", "--time"]) {
+        SeekTarget::Timestamp(parse_timestamp(&time).unwrap_or_else(|e| {
             output_error(&e.to_string());
             std::process::exit(1);
-        }),
-        Subject::Topic => list_topics().unwrap_or_else(|e|{
+        }))
+    } else if let Some(event_id) = get_option(&["-e", "--eid"]) {
+        SeekTarget::EventId(event_id)
+    } else if args.iter().any(|arg| arg == "--earliest") {
+        SeekTarget::Earliest
+    } else if args.iter().any(|arg| arg == "--latest") {
+        SeekTarget::Latest
+    } else {
+        output_error("Missing seek target. Use --offset, --time, --eid, --earliest or --latest.");
+        std::process::exit(1);
+    };
+    consumer.seek(&target).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    println!("{}", consumer);
+}
+
+fn config(){
+    let subject = get_subject();
+    match subject {
+        Subject::Topic => config_topic(),
+        _ => {
+            output_error("Only topics can be configured.");
+            std::process::exit(1);
+        },
+    }
+}
+
+fn config_topic(){
+    let topic = get_topic();
+    let topic = Topic::hydrate(&topic).unwrap_or_else(|e|{
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    let mut settings = topic.settings().unwrap_or_else(|e|{
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    for setting in args().skip(4) {
+        let (key, value) = setting.split_once('=').unwrap_or_else(|| {
+            output_error("Settings must be formatted as key=value.");
+            std::process::exit(1);
+        });
+        settings = topic.configure(key, value).unwrap_or_else(|e|{
             output_error(&e.to_string());
             std::process::exit(1);
-        }),
+        });
     }
+    println!("{}", settings);
 }
 
-fn write(){
-    let token = env::args()
+fn list_subject(){
+    let subject = get_subject();
+    let items = match subject {
+        Subject::Producer => list_producers().map(|producers| producers.iter().map(|producer| producer.to_string()).collect()),
+        Subject::Consumer => list_consumers().map(|consumers| consumers.iter().map(|consumer| consumer.to_string()).collect()),
+        Subject::Topic => get_topics().map(|topics| topics.iter().map(|topic| topic.to_string()).collect()),
+        Subject::Credential => list_credentials().map(|credentials| credentials.iter().map(|credential| credential.to_string()).collect()),
+    };
+    let items:Vec<String> = items.unwrap_or_else(|e|{
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    for item in items {
+        println!("{}", item);
+    }
+}
+
+// Token of the write & read commands, which take no subject
+fn get_data_token() -> String {
+    let token = args()
                     .nth(2)
                     .unwrap_or_else(|| {
                         output_error("Missing token.");
                         std::process::exit(1);
                     })
                     .to_lowercase();
-    let content = env::args()
+    return token;
+}
+
+// Topic a write, read or seek is meant for, required by servers & by tokens granted
+// several topics
+fn get_data_topic() -> Option<String> {
+    return get_option(&["--topic"]);
+}
+
+// Event of the write command: content plus any --key & --header name=value
+fn get_event() -> Event {
+    let content = args()
                     .nth(3)
                     .unwrap_or_else(|| {
                         output_error("Missing content.");
                         std::process::exit(1);
                     });
-    let producer = Producer::hydrate(&token).unwrap_or_else(|e| {
+    let mut key:Option<String> = None;
+    let mut headers:Vec<(String, String)> = Vec::new();
+    let args = args().collect::<Vec<String>>();
+    for i in 4..args.len() {
+        match args[i].as_str() {
+            "-k" | "--key" => {
+                key = args.get(i+1).cloned();
+            }
+            "-H" | "--header" => {
+                let header = args.get(i+1).and_then(|h| h.split_once('=')).unwrap_or_else(|| {
+                    output_error("Headers must be formatted as name=value.");
+                    std::process::exit(1);
+                });
+                headers.push((header.0.to_lowercase(), header.1.to_owned()));
+            }
+            _ => {}
+        }
+    }
+    return Event::new(content.into_bytes(), key, headers);
+}
+
+fn write(){
+    let token = get_data_token();
+    let event = get_event();
+    let producer = Producer::hydrate(&token).and_then(|producer| producer.for_topic(get_data_topic().as_deref())).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    let content = content.as_bytes();
-    if content.len() > usize::MAX {
-        output_error("Oversized payload.");
+    let settings = Topic::hydrate(&producer.topic).and_then(|topic| topic.settings()).unwrap_or_else(|e| {
+        output_error(&e.to_string());
         std::process::exit(1);
-    }
-    producer.write(&content).unwrap_or_else(|e| {
+    });
+    // Only the topic's maximum applies, the server's maximum guards its request bodies
+    settings.check_message_size(event.content.len() as u64, u64::MAX).unwrap_or_else(|e| {
+        output_error(&e.to_string());
+        std::process::exit(1);
+    });
+    // There is no group commit outside of the server, interval topics sync every write
+    let sync = settings.durability.unwrap_or(Durability::Always) != Durability::None;
+    producer.write(&event, sync).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -357,18 +653,16 @@ fn write(){
 }
 
 fn read(){
-    let token = env::args()
-                    .nth(2)
-                    .unwrap_or_else(|| {
-                        output_error("Missing token.");
-                        std::process::exit(1);
-                    })
-                    .to_lowercase();
-    let mut consumer = Consumer::hydrate(&token).unwrap_or_else(|e| {
+    let token = get_data_token();
+    let mut consumer = Consumer::hydrate(&token).and_then(|consumer| consumer.for_topic(get_data_topic().as_deref())).unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
-    let event = consumer.read(true).unwrap_or_else(|e| {
+    let event = match get_offset() {
+        Some(offset) => consumer.read_offset(offset),
+        None => consumer.read(true),
+    };
+    let event = event.unwrap_or_else(|e| {
         output_error(&e.to_string());
         std::process::exit(1);
     });
@@ -0,0 +1,258 @@
+#![allow(unused)]
+
+use std::{collections::HashMap, io::{self, BufWriter, Write, Seek, SeekFrom, BufReader, Read}};
+use super::storage::{storage, lock_topic, OpenOptions, StorageFile};
+use anyhow::Result;
+use crate::subjects::{topic::Topic, consumer::Consumer, event::{Event, now}};
+use super::errors::CorruptRecord;
+use super::consumers::{consumers_exists, get_topic_consumers, update_consumer_in_config};
+use super::indexes::{get_base_offset, ensure_index, rebuild_index_from, delete_index, CompactionMap, CompactionEntry, write_compaction_map};
+use super::topics::{get_topic_file, read_segment_header, read_record, next_record_position};
+
+// Compaction rewrites the closed log files of a topic keeping only the newest event
+// per key. Events without a key are always kept. Tombstones (keyed events without
+// content) are kept until they are older than the tombstone retention, so consumers
+// get to see the delete. The current log file is never rewritten, but its events
+// still replace older events of the same key. Kept events keep their message offset
+// & event ID, see the compaction map in indexes.
+
+pub struct Compaction {
+    pub dropped: u64,
+    // Log files left as they are, with the position of their first corrupt record
+    pub skipped: Vec<(u64, u64)>,
+}
+
+pub fn compact_topic(topic: &Topic, tombstone_retention_ms: u64) -> Result<Compaction> {
+    let mut compaction = Compaction { dropped: 0, skipped: Vec::new() };
+    if topic.first_log_file == topic.curr_log_file {
+        return Ok(compaction);
+    }
+
+    // Writes wait for the scan, so it sees every record up to the end of the log
+    let lock = lock_topic(&topic.name, false)?;
+    let (latest, corrupt) = find_latest_keys(topic)?;
+    drop(lock);
+
+    for log_file in topic.first_log_file..topic.curr_log_file {
+        // A log file with a corrupt record is left as is until it is deleted
+        if let Some(position) = corrupt.get(&log_file) {
+            compaction.skipped.push((log_file, *position));
+            continue;
+        }
+        compaction.dropped += compact_log_file(topic, log_file, &latest, tombstone_retention_ms)?;
+    }
+    return Ok(compaction);
+}
+
+// Log file & position of the newest event of every key
+type LatestKeys = HashMap<String, (u64, u64)>;
+
+// The latest keys, and the position of the first corrupt record of every log file
+// that has one. Corrupt records whose length can still be trusted are skipped, the
+// rest of a log file is lost otherwise.
+fn find_latest_keys(topic: &Topic) -> Result<(LatestKeys, HashMap<u64, u64>)> {
+    let mut latest:LatestKeys = HashMap::new();
+    let mut corrupt:HashMap<u64, u64> = HashMap::new();
+    for log_file in topic.first_log_file..=topic.curr_log_file {
+        let file = get_topic_file(&topic.name, &log_file)?;
+        let file_length = file.len()?;
+        let header = read_segment_header(&file)?;
+        let map = CompactionMap::open(&topic.name, &log_file)?;
+        let mut reader = BufReader::new(&file);
+        let mut position = header.length;
+        while position < file_length {
+            match read_record(&file, &header, map.as_ref(), &topic.name, log_file, position) {
+                Ok((event, next_position)) => {
+                    if let Some(key) = event.key {
+                        latest.insert(key, (log_file, position));
+                    }
+                    position = next_position;
+                }
+                Err(e) => {
+                    let skippable = match e.downcast_ref::<CorruptRecord>() {
+                        Some(record) => record.skip_eid.is_some(),
+                        None => return Err(e),
+                    };
+                    corrupt.entry(log_file).or_insert(position);
+                    if !skippable {
+                        break;
+                    }
+                    position = next_record_position(&mut reader, &header, position)?;
+                }
+            }
+        }
+    }
+    return Ok((latest, corrupt));
+}
+
+fn compact_log_file(topic: &Topic, log_file: u64, latest: &LatestKeys, tombstone_retention_ms: u64) -> Result<u64> {
+    let _lock = lock_topic(&topic.name, true)?;
+    let file = get_topic_file(&topic.name, &log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+
+    // Offsets & positions from before the first compaction of the log file
+    let previous_map = CompactionMap::open(&topic.name, &log_file)?;
+
+    // Only rewrite log files that lose at least one event
+    let mut dropped:u64 = 0;
+    let mut records:u64 = 0;
+    let mut position = header.length;
+    while position < file_length {
+        let (event, next_position) = read_record(&file, &header, previous_map.as_ref(), &topic.name, log_file, position)?;
+        if !should_keep(&event, (log_file, position), latest, tombstone_retention_ms) {
+            dropped += 1;
+        }
+        records += 1;
+        position = next_position;
+    }
+    if dropped == 0 {
+        return Ok(0);
+    }
+
+    let (record_count, original_length) = match &previous_map {
+        Some(map) => (map.record_count, map.length),
+        None => (records, file_length),
+    };
+    let mut entries:Vec<CompactionEntry> = Vec::new();
+
+    // Consumers in this log file, ordered by position so they can be moved along
+    // with the records they point at
+    let mut consumers:Vec<Consumer> = Vec::new();
+    if consumers_exists() {
+        consumers = get_topic_consumers(&topic.name)?
+                        .into_iter()
+                        .filter(|consumer| consumer.log_file == log_file)
+                        .collect();
+        consumers.sort_by_key(|consumer| consumer.log_offset);
+    }
+    let mut next_consumer = 0;
+
+    let compacted_path = format!("logs/{}/{}.compacting", topic.name, log_file);
+    let compacted_file = OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(&compacted_path)?;
+    let mut writer = BufWriter::new(&compacted_file);
+    let mut reader = BufReader::new(&file);
+
+    // Keep the header as is, the record format doesn't change
+    reader.seek(SeekFrom::Start(0))?;
+    io::copy(&mut (&mut reader).take(header.length), &mut writer)?;
+    let mut written = header.length;
+
+    let mut position = header.length;
+    let mut record:u64 = 0;
+    while position < file_length {
+        let (event, next_position) = read_record(&file, &header, previous_map.as_ref(), &topic.name, log_file, position)?;
+
+        // Consumers on a dropped record move to the next kept one
+        while next_consumer < consumers.len() && consumers[next_consumer].log_offset <= position {
+            consumers[next_consumer].log_offset = written;
+            next_consumer += 1;
+        }
+
+        if should_keep(&event, (log_file, position), latest, tombstone_retention_ms) {
+            let (offset, original_position) = match &previous_map {
+                Some(map) => {
+                    let entry = map.entry(record)?;
+                    (entry.offset, entry.original_position)
+                }
+                None => (record, position),
+            };
+            entries.push(CompactionEntry { offset, original_position, position: written });
+
+            reader.seek(SeekFrom::Start(position))?;
+            io::copy(&mut (&mut reader).take(next_position - position), &mut writer)?;
+            written += next_position - position;
+        }
+        record += 1;
+        position = next_position;
+    }
+    for consumer in consumers.iter_mut().skip(next_consumer) {
+        consumer.log_offset = written;
+    }
+
+    writer.flush()?;
+    drop(writer);
+    compacted_file.sync_data()?;
+
+    // The base offset of the next log file is counted from this one until the next
+    // log file has an index, so it has to have one before the records go
+    ensure_index(&topic.name, &(log_file + 1))?;
+    let base_offset = get_base_offset(&topic.name, &log_file)?;
+    storage().rename(&compacted_path, &format!("logs/{}/{}", topic.name, log_file))?;
+    write_compaction_map(&topic.name, &log_file, record_count, original_length, &entries)?;
+    delete_index(&topic.name, &log_file)?;
+    rebuild_index_from(&topic.name, &log_file, base_offset)?;
+
+    for consumer in consumers.iter() {
+        update_consumer_in_config(consumer)?;
+    }
+
+    return Ok(dropped);
+}
+
+fn should_keep(event: &Event, location: (u64, u64), latest: &LatestKeys, tombstone_retention_ms: u64) -> bool {
+    let key = match &event.key {
+        Some(key) => key,
+        None => return true,
+    };
+    if latest.get(key) != Some(&location) {
+        return false;
+    }
+    return !event.content.is_empty() || now().saturating_sub(event.timestamp) <= tombstone_retention_ms;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::test_storage;
+    use super::super::indexes::{find_offset, get_offset_range};
+    use super::super::topics::{write, read_at};
+
+    #[test]
+    fn keeps_offsets_and_event_ids_of_kept_events() {
+        let _storage = test_storage();
+        let topic = Topic::new("compacted".to_string()).unwrap();
+        topic.configure("segment_bytes", "1000").unwrap();
+        let events:u64 = 60;
+        let key = |event: u64| if event % 6 == 5 { None } else { Some(format!("key {}", event % 4)) };
+        for event in 0..events {
+            write(&topic.name, &Event::new(format!("event {}", event).into_bytes(), key(event), Vec::new()), false).unwrap();
+        }
+        let topic = Topic::hydrate(&topic.name).unwrap();
+
+        let read_offset = |offset: u64| {
+            let (log_file, position) = find_offset(&topic, offset).unwrap();
+            let event = read_at(&topic.name, &log_file, position).unwrap();
+            return (log_file, event.eid, event.content);
+        };
+        let before:Vec<(u64, String, Vec<u8>)> = (0..events).map(read_offset).collect();
+
+        let compaction = compact_topic(&topic, 0).unwrap();
+        assert!(compaction.dropped > 0);
+        assert!(compaction.skipped.is_empty());
+        assert_eq!(get_offset_range(&topic).unwrap(), (0, events));
+
+        // Dropped offsets resolve to the next kept event
+        let is_kept = |event: u64| {
+            return before[event as usize].0 == topic.curr_log_file || key(event).is_none() || (event + 1..events).all(|later| key(later) != key(event));
+        };
+        let mut kept:u64 = 0;
+        for event in 0..events {
+            let next_kept = (event..events).find(|later| is_kept(*later)).unwrap();
+            let (_, eid, content) = read_offset(event);
+            assert_eq!((eid, content), (before[next_kept as usize].1.clone(), before[next_kept as usize].2.clone()));
+            if is_kept(event) {
+                kept += 1;
+            }
+        }
+        assert_eq!(compaction.dropped, events - kept);
+
+        // Compacting again finds nothing left to drop
+        assert_eq!(compact_topic(&topic, 0).unwrap().dropped, 0);
+        assert_eq!(read_offset(0).1, before[(0..events).find(|event| is_kept(*event)).unwrap() as usize].1);
+    }
+}
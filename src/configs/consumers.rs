@@ -1,70 +1,84 @@
 #![allow(unused)]
 
-use std::{path::Path, fs::{self, File, OpenOptions}, io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read}};
+use std::io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read};
+use super::storage::{storage, lock_registries, OpenOptions, StorageFile};
+use super::errors::InvalidToken;
 use anyhow::Result;
-use crate::subjects::{consumer::Consumer, keys::generate_key};
+use super::keys::read_key_expiry;
+use crate::subjects::{consumer::Consumer, event::now, keys::{generate_key, hash_key, is_deleted_key, position_key, position_owner, KeyExpiry, KEY_LENGTH, KEY_EXPIRY_FLAG, KEY_EXPIRY_LENGTH}};
 
 fn create_configs_dir() -> Result<()> {
-    let path = Path::new("sailfish/configs");
-    if !path.exists() {
-        fs::create_dir_all(path)?;
+    let path = "configs";
+    if !storage().exists(path) {
+        storage().create_dir_all(path)?;
     }
     return Ok(());
 }
 
-pub fn create_consumer_file() -> Result<File> {
-    let path = format!("sailfish/configs/consumers");
-    let path = Path::new(&path);
+pub fn create_consumer_file() -> Result<StorageFile> {
+    let path = "configs/consumers";
     let file = OpenOptions::new()
                     .write(true)
                     .create(true)
+                    .truncate(false)
                     .open(path)?;
     return Ok(file);
 }
 
 pub fn consumers_exists() -> bool {
-    let path = format!("sailfish/configs/consumers");
-    let path = Path::new(&path);
-    return path.exists();
+    let path = "configs/consumers";
+    return storage().exists(path);
 }
 
 pub fn add_consumer_to_config(consumer: &mut Consumer) -> Result<()> {
+    let _lock = lock_registries(true)?;
     create_configs_dir()?;
-    let path = Path::new("sailfish/configs/consumers");
+    // Only the salted hash of the key is stored, always 36 bytes
+    consumer.stored_key = hash_key(&consumer.key);
+    return append_consumer(consumer);
+}
+
+// Appends the record of the consumer with its stored key, the registry lock must be held
+fn append_consumer(consumer: &mut Consumer) -> Result<()> {
+    let path = "configs/consumers";
     let file = OpenOptions::new()
                     .append(true)
+                    .create(true)
                     .open(path)?;
 
     let mut writer = BufWriter::new(&file);
     consumer.offset = writer.seek(SeekFrom::End(0))?;
     writer.seek(SeekFrom::Start(consumer.offset))?;
 
-    // We don't need to track the length of the key because it is a UUIDv4
-    // It will always be 36 bytes
-    writer.write_all(consumer.key.as_bytes())?;
+    writer.write_all(&consumer.stored_key)?;
 
+    // Topic length is flagged as the record ending with the key expiry
     let topic_bytes = consumer.topic.as_bytes();
     let topic_length = topic_bytes.len() as u64;
-    writer.write_all(&topic_length.to_be_bytes())?;
+    writer.write_all(&(topic_length | KEY_EXPIRY_FLAG).to_be_bytes())?;
     writer.write_all(topic_bytes)?;
 
     // Write 16 bytes of log file data (8 bytes ea)
     writer.write_all(&consumer.log_file.to_be_bytes())?;
     writer.write_all(&consumer.log_offset.to_be_bytes())?;
 
+    let expiry = *consumer.expiry.get_or_insert(KeyExpiry::new(None));
+    writer.write_all(&expiry.to_bytes())?;
+
     writer.flush()?;
 
     return Ok(());
 }
 
 pub fn update_consumer_in_config(consumer: &Consumer) -> Result<()> {
-    let path = Path::new("sailfish/configs/consumers");
+    let _lock = lock_registries(true)?;
+    let path = "configs/consumers";
     let file = OpenOptions::new()
                     .write(true)
                     .open(path)?;
 
     let mut writer = BufWriter::with_capacity(16, &file);
-    writer.seek(SeekFrom::Start(consumer.offset + 36 + 8 + consumer.topic.as_bytes().len() as u64))?;
+    writer.seek(SeekFrom::Start(consumer.offset + 36 + 8 + consumer.topic.len() as u64))?;
 
     writer.write_all(&consumer.log_file.to_be_bytes())?;
     writer.write_all(&consumer.log_offset.to_be_bytes())?;
@@ -74,29 +88,28 @@ pub fn update_consumer_in_config(consumer: &Consumer) -> Result<()> {
     return Ok(());
 }
 
-pub fn get_consumer(offset: u64) -> Result<Consumer> {
-    let path = Path::new("sailfish/configs/consumers");
-    let file = OpenOptions::new()
-                    .read(true)
-                    .open(path)?;
-
-    let mut reader = BufReader::new(&file);
-    reader.seek(SeekFrom::Start(offset))?;
-
+// Returns the consumer & the length of its record
+fn read_consumer(reader: &mut BufReader<&StorageFile>, offset: u64, file_length: u64) -> Result<(Consumer, u64)> {
     // Read key
-    let mut key_buffer = [0u8; 36];
-    reader.read_exact(&mut key_buffer)?;
-    let key = std::str::from_utf8(&key_buffer)?;
+    let mut stored_key = [0u8; KEY_LENGTH];
+    reader.read_exact(&mut stored_key)?;
 
     // Read topic length
     let mut topic_length_buffer = [0u8; 8];
     reader.read_exact(&mut topic_length_buffer)?;
     let topic_length:u64 = u64::from_be_bytes(topic_length_buffer);
+    let has_expiry = topic_length & KEY_EXPIRY_FLAG != 0;
+    let topic_length = topic_length & !KEY_EXPIRY_FLAG;
+
+    // Tokens with an offset inside another record read garbage lengths
+    if topic_length > file_length.saturating_sub(offset + 44) {
+        return Err(InvalidToken.into());
+    }
 
     // Read topic
     let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
     reader.read_exact(&mut topic_buffer[..])?;
-    let topic = std::str::from_utf8(&topic_buffer)?;
+    let topic = std::str::from_utf8(&topic_buffer).map_err(|_| InvalidToken)?;
 
     // Read log file
     let mut log_file_buffer = [0u8; 8];
@@ -108,19 +121,39 @@ pub fn get_consumer(offset: u64) -> Result<Consumer> {
     reader.read_exact(&mut log_file_offset_buffer)?;
     let log_offset = u64::from_be_bytes(log_file_offset_buffer);
 
-    let producer = Consumer{
+    let expiry = read_key_expiry(reader, has_expiry)?;
+
+    let consumer = Consumer{
         topic: topic.to_owned(),
         offset,
-        key: key.to_owned(),
+        key: String::new(),
+        stored_key,
+        expiry,
         log_file,
         log_offset,
     };
+    let length = 36 + 8 + topic_length + 16 + if has_expiry { KEY_EXPIRY_LENGTH } else { 0 };
+
+    return Ok((consumer, length));
+}
+
+pub fn get_consumer(offset: u64) -> Result<Consumer> {
+    let _lock = lock_registries(false)?;
+    let path = "configs/consumers";
+    let file = OpenOptions::new()
+                    .read(true)
+                    .open(path)?;
+
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(offset))?;
+    let (consumer, _) = read_consumer(&mut reader, offset, file.len()?)?;
 
-    return Ok(producer);
+    return Ok(consumer);
 }
 
 pub fn delete_consumer(consumer: &Consumer) -> Result<()> {
-    let path = Path::new("sailfish/configs/consumers");
+    let _lock = lock_registries(true)?;
+    let path = "configs/consumers";
     let file = OpenOptions::new()
                     .write(true)
                     .open(path)?;
@@ -128,33 +161,59 @@ pub fn delete_consumer(consumer: &Consumer) -> Result<()> {
     let mut writer = BufWriter::with_capacity(36, &file);
     writer.seek(SeekFrom::Start(consumer.offset))?;
 
-    // Overwrite key with null bytes (36)
+    // Overwrite key with null bytes (36), the previous key goes with it
     let null_bytes = [0u8; 36];
     writer.write_all(&null_bytes)?;
 
+    // Position records of a consumer with several topics go too
+    for position in read_consumers()?.iter().filter(|position| position_owner(&position.stored_key) == Some(consumer.offset)) {
+        writer.seek(SeekFrom::Start(position.offset))?;
+        writer.write_all(&null_bytes)?;
+    }
+
     writer.flush()?;
 
     return Ok(());
 }
 
-pub fn reroll_consumer_key(consumer: &Consumer) -> Result<String> {
-    let path = Path::new("sailfish/configs/consumers");
+// Returns the new key with the record's new stored key & expiry
+pub fn reroll_consumer_key(consumer: &Consumer, grace_ms: u64, expires_at: Option<u64>) -> Result<(String, [u8; KEY_LENGTH], Option<KeyExpiry>)> {
+    let _lock = lock_registries(true)?;
+    let path = "configs/consumers";
     let file = OpenOptions::new()
+                    .read(true)
                     .write(true)
                     .open(path)?;
 
-    let mut writer = BufWriter::with_capacity(36, &file);
-    writer.seek(SeekFrom::Start(consumer.offset))?;
-
+    // The key being replaced is read under the lock, in case of a concurrent reroll
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(consumer.offset))?;
+    let (current, length) = read_consumer(&mut reader, consumer.offset, file.len()?)?;
+    drop(reader);
 
     let new_key = generate_key();
-    writer.write_all(new_key.as_bytes())?;
+    let stored_key = hash_key(&new_key);
+    let expiry = current.expiry.map(|expiry| expiry.reroll(&current.stored_key, grace_ms, expires_at, now()));
 
-    return Ok(new_key);
+    let mut writer = BufWriter::with_capacity(36, &file);
+    writer.seek(SeekFrom::Start(consumer.offset))?;
+    writer.write_all(&stored_key)?;
+    if let Some(expiry) = &expiry {
+        writer.seek(SeekFrom::Start(consumer.offset + length - KEY_EXPIRY_LENGTH))?;
+        writer.write_all(&expiry.to_bytes())?;
+    }
+    writer.flush()?;
+
+    return Ok((new_key, stored_key, expiry));
 }
 
-pub fn list_consumers() -> Result<()> {
-    let path = Path::new("sailfish/configs/consumers");
+// Consumers that haven't been deleted, including position records. The registry
+// lock must be held.
+fn read_consumers() -> Result<Vec<Consumer>> {
+    if !consumers_exists() {
+        return Ok(Vec::new());
+    }
+    let path = "configs/consumers";
     let file = OpenOptions::new()
                     .read(true)
                     .open(path)?;
@@ -162,106 +221,81 @@ pub fn list_consumers() -> Result<()> {
     let mut reader = BufReader::new(&file);
     reader.seek(SeekFrom::Start(0))?;
 
-    let total_bytes = file.metadata()?.len();
+    let total_bytes = file.len()?;
+    let mut consumers:Vec<Consumer> = Vec::new();
     let mut bytes_read = 0;
 
-    loop {
-        if bytes_read == total_bytes {
-            break;
-        }
-
-        // Read key
-        let mut key_buffer = [0u8; 36];
-        reader.read_exact(&mut key_buffer)?;
-        let key = std::str::from_utf8(&key_buffer)?;
-
-        // Read topic length
-        let mut topic_length_buffer = [0u8; 8];
-        reader.read_exact(&mut topic_length_buffer)?;
-        let topic_length:u64 = u64::from_be_bytes(topic_length_buffer);
-
-        // Read topic
-        let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
-        reader.read_exact(&mut topic_buffer[..])?;
-        let topic = std::str::from_utf8(&topic_buffer)?;
-
-        // Read log file
-        let mut log_file_buffer = [0u8; 8];
-        reader.read_exact(&mut log_file_buffer)?;
-        let log_file = u64::from_be_bytes(log_file_buffer);
-
-        // Read log file offset
-        let mut log_file_offset_buffer = [0u8; 8];
-        reader.read_exact(&mut log_file_offset_buffer)?;
-        let log_offset = u64::from_be_bytes(log_file_offset_buffer);
-
-        if !key.escape_default().to_string().contains("\\u{0}") {
-            let producer = Consumer{
-                topic: topic.to_owned(),
-                offset: bytes_read,
-                key: key.to_owned(),
-                log_file,
-                log_offset,
-            };
-            println!("{}", producer);
+    while bytes_read < total_bytes {
+        let (consumer, length) = read_consumer(&mut reader, bytes_read, total_bytes)?;
+        // Only the hash of the key is known, the key itself stays empty
+        if !is_deleted_key(&consumer.stored_key) {
+            consumers.push(consumer);
         }
-
-        bytes_read += 36 + 8 + topic_length + 16;
+        bytes_read += length;
     }
 
-    return Ok(());
+    return Ok(consumers);
 }
 
-pub fn get_oldest_active_log_file(topic: &str) -> Result<Option<u64>> {
-    let path = Path::new("sailfish/configs/consumers");
-    let file = OpenOptions::new()
-                    .read(true)
-                    .open(path)?;
+pub fn list_consumers() -> Result<Vec<Consumer>> {
+    let _lock = lock_registries(false)?;
+    let consumers = read_consumers()?
+                        .into_iter()
+                        .filter(|consumer| position_owner(&consumer.stored_key).is_none())
+                        .collect();
+    return Ok(consumers);
+}
 
-    let mut reader = BufReader::new(&file);
-    reader.seek(SeekFrom::Start(0))?;
+// Consumers reading the topic, position records included
+pub fn get_topic_consumers(topic: &str) -> Result<Vec<Consumer>> {
+    let _lock = lock_registries(false)?;
+    let consumers = read_consumers()?
+                        .into_iter()
+                        .filter(|consumer| consumer.topic == topic)
+                        .collect();
+    return Ok(consumers);
+}
 
-    let total_bytes = file.metadata()?.len();
-    let mut bytes_read = 0;
-    let mut oldest_log_file:Option<u64> = None;
+pub fn get_oldest_active_log_file(topic: &str) -> Result<Option<u64>> {
+    let oldest_log_file = get_topic_consumers(topic)?
+                            .iter()
+                            .map(|consumer| consumer.log_file)
+                            .min();
+    return Ok(oldest_log_file);
+}
 
-    loop {
-        if bytes_read == total_bytes {
-            break;
-        }
+// Position of a consumer with several topics in one of them, added at the given log
+// file when the consumer hasn't read the topic before
+fn find_consumer_position(consumer_offset: u64, topic: &str) -> Result<Option<Consumer>> {
+    let position = read_consumers()?
+                        .into_iter()
+                        .find(|position| position_owner(&position.stored_key) == Some(consumer_offset) && position.topic == topic);
+    return Ok(position);
+}
 
-        let mut key_buffer = [0u8; 36];
-        reader.read_exact(&mut key_buffer)?;
-        let key = std::str::from_utf8(&key_buffer)?;
-
-        // Read topic length
-        let mut topic_length_buffer = [0u8; 8];
-        reader.read_exact(&mut topic_length_buffer)?;
-        let topic_length:u64 = u64::from_be_bytes(topic_length_buffer);
-
-        if !key.escape_default().to_string().contains("\\u{0}") {
-            let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
-            reader.read_exact(&mut topic_buffer[..])?;
-            let consumer_topic = std::str::from_utf8(&topic_buffer)?;
-
-            if topic == consumer_topic {
-                let mut log_file_buffer = [0u8; 8];
-                reader.read_exact(&mut log_file_buffer)?;
-                let log_file = u64::from_be_bytes(log_file_buffer);
-
-                if log_file < oldest_log_file.unwrap_or(u64::MAX) {
-                    oldest_log_file = Some(log_file);
-                }
-            }
-        } else {
-            reader.seek(SeekFrom::Current(topic_length as i64));
+// Reads share the registry, only the first read of a topic takes it exclusively
+// to append the position, after checking nobody appended it in between
+pub fn get_or_add_consumer_position(consumer_offset: u64, topic: &str, log_file: u64) -> Result<Consumer> {
+    {
+        let _lock = lock_registries(false)?;
+        if let Some(position) = find_consumer_position(consumer_offset, topic)? {
+            return Ok(position);
         }
-
-        // Skip log file offset
-        reader.seek(SeekFrom::Current(8))?;
-
-        bytes_read += 36 + 8 + topic_length + 16;
     }
 
-    return Ok(oldest_log_file);
+    let _lock = lock_registries(true)?;
+    if let Some(position) = find_consumer_position(consumer_offset, topic)? {
+        return Ok(position);
+    }
+    let mut position = Consumer{
+        topic: topic.to_owned(),
+        offset: 0,
+        key: String::new(),
+        stored_key: position_key(consumer_offset),
+        expiry: Some(KeyExpiry::new(None)),
+        log_file,
+        log_offset: 0,
+    };
+    append_consumer(&mut position)?;
+    return Ok(position);
 }
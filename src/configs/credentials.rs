@@ -0,0 +1,192 @@
+#![allow(unused)]
+
+use std::io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read};
+use crate::subjects::{credential::{Credential, Role}, keys::{generate_key, hash_key, is_deleted_key, KEY_LENGTH}};
+use super::storage::{storage, lock_registries, OpenOptions, StorageFile};
+use super::errors::{InvalidToken, InvalidInput};
+use anyhow::Result;
+
+// Record layout: salted key hash (36 bytes) + role (1 byte) + topic length (8 bytes) + topic.
+// Only topic owners have a topic, the others store an empty one.
+
+fn create_configs_dir() -> Result<()>{
+    let path = "configs";
+    if !storage().exists(path) {
+        storage().create_dir_all(path)?;
+    }
+    return Ok(());
+}
+
+pub fn credentials_exists() -> bool {
+    let path = "configs/credentials";
+    return storage().exists(path);
+}
+
+pub fn add_credential_to_config(credential: &mut Credential) -> Result<()> {
+    let _lock = lock_registries(true)?;
+    create_configs_dir()?;
+    let path = "configs/credentials";
+    let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)?;
+
+    let mut writer = BufWriter::new(&file);
+    credential.offset = writer.seek(SeekFrom::End(0))?;
+    writer.seek(SeekFrom::Start(credential.offset))?;
+
+    credential.stored_key = hash_key(&credential.key);
+    writer.write_all(&credential.stored_key)?;
+    writer.write_all(&[credential.role.to_byte()])?;
+
+    let topic_bytes = credential.topic.as_deref().unwrap_or("").as_bytes();
+    let topic_length = topic_bytes.len() as u64;
+    writer.write_all(&topic_length.to_be_bytes())?;
+    writer.write_all(topic_bytes)?;
+
+    writer.flush()?;
+
+    return Ok(());
+}
+
+fn read_credential(reader: &mut BufReader<&StorageFile>, offset: u64, file_length: u64) -> Result<Credential> {
+    // Read key
+    let mut stored_key = [0u8; KEY_LENGTH];
+    reader.read_exact(&mut stored_key)?;
+
+    // Read role
+    let mut role_buffer = [0u8; 1];
+    reader.read_exact(&mut role_buffer)?;
+    let role = Role::from_byte(role_buffer[0]).ok_or(InvalidToken)?;
+
+    // Read topic length
+    let mut topic_length_buffer = [0u8; 8];
+    reader.read_exact(&mut topic_length_buffer)?;
+    let topic_length:u64 = u64::from_be_bytes(topic_length_buffer);
+
+    // Tokens with an offset inside another record read garbage lengths
+    if topic_length > file_length.saturating_sub(offset + 45) {
+        return Err(InvalidToken.into());
+    }
+
+    // Read topic
+    let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
+    reader.read_exact(&mut topic_buffer[..])?;
+    let topic = std::str::from_utf8(&topic_buffer).map_err(|_| InvalidToken)?;
+
+    let credential = Credential {
+        offset,
+        key: String::new(),
+        stored_key,
+        role,
+        topic: if topic.is_empty() { None } else { Some(topic.to_owned()) },
+    };
+    return Ok(credential);
+}
+
+pub fn get_credential(offset: u64) -> Result<Credential> {
+    let _lock = lock_registries(false)?;
+    let path = "configs/credentials";
+    let file = OpenOptions::new()
+                    .read(true)
+                    .open(path)?;
+
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(offset))?;
+    return read_credential(&mut reader, offset, file.len()?);
+}
+
+// The last admin credential is never deleted. The count & the delete happen under
+// the same lock, so two deletes can't both pass the count.
+pub fn delete_credential(credential: &Credential) -> Result<()> {
+    let _lock = lock_registries(true)?;
+    if credential.role == Role::Admin && read_credentials()?.iter().filter(|credential| credential.role == Role::Admin).count() == 1 {
+        return Err(InvalidInput { reason: "The last admin credential can't be deleted.".to_string() }.into());
+    }
+    return null_credential_key(credential.offset);
+}
+
+// Deletes the owner credentials of a deleted topic, so they don't get access to a
+// new topic with the same name
+pub fn delete_topic_credentials(topic: &str) -> Result<()> {
+    if !credentials_exists() {
+        return Ok(());
+    }
+    let _lock = lock_registries(true)?;
+    for credential in read_credentials()? {
+        if credential.role == Role::Owner && credential.topic.as_deref() == Some(topic) {
+            null_credential_key(credential.offset)?;
+        }
+    }
+    return Ok(());
+}
+
+fn null_credential_key(offset: u64) -> Result<()> {
+    let path = "configs/credentials";
+    let file = OpenOptions::new()
+                    .write(true)
+                    .open(path)?;
+
+    let mut writer = BufWriter::with_capacity(36, &file);
+    writer.seek(SeekFrom::Start(offset))?;
+
+    // Overwrite key with null bytes (36)
+    let null_bytes = [0u8; 36];
+    writer.write_all(&null_bytes)?;
+
+    writer.flush()?;
+
+    return Ok(());
+}
+
+pub fn reroll_credential_key(credential: &Credential) -> Result<String> {
+    let _lock = lock_registries(true)?;
+    let path = "configs/credentials";
+    let file = OpenOptions::new()
+                    .write(true)
+                    .open(path)?;
+
+    let mut writer = BufWriter::with_capacity(36, &file);
+    writer.seek(SeekFrom::Start(credential.offset))?;
+
+    let new_key = generate_key();
+    writer.write_all(&hash_key(&new_key))?;
+    writer.flush()?;
+
+    return Ok(new_key);
+}
+
+// Credentials that haven't been deleted
+pub fn list_credentials() -> Result<Vec<Credential>> {
+    if !credentials_exists() {
+        return Ok(Vec::new());
+    }
+    let _lock = lock_registries(false)?;
+    return read_credentials();
+}
+
+// Callers hold the registry lock
+fn read_credentials() -> Result<Vec<Credential>> {
+    let path = "configs/credentials";
+    let file = OpenOptions::new()
+                    .read(true)
+                    .open(path)?;
+
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(0))?;
+
+    let total_bytes = file.len()?;
+    let mut bytes_read = 0;
+    let mut credentials:Vec<Credential> = Vec::new();
+
+    while bytes_read < total_bytes {
+        let credential = read_credential(&mut reader, bytes_read, total_bytes)?;
+        let topic_length = credential.topic.as_deref().map(|topic| topic.len() as u64).unwrap_or(0);
+        if !is_deleted_key(&credential.stored_key) {
+            credentials.push(credential);
+        }
+        bytes_read += 36 + 1 + 8 + topic_length;
+    }
+
+    return Ok(credentials);
+}
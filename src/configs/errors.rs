@@ -0,0 +1,159 @@
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub struct CorruptRecord {
+    pub log_file: u64,
+    pub log_offset: u64,
+    // Event ID that moves a consumer past the corrupt record, when the
+    // length prefix could still be trusted
+    pub skip_eid: Option<String>,
+}
+
+impl Display for CorruptRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Corrupt record at offset {} in log file {}.", self.log_offset, self.log_file);
+    }
+}
+
+impl std::error::Error for CorruptRecord {}
+
+// An event ID that doesn't point at a record boundary of a retained log file
+#[derive(Debug)]
+pub struct InvalidEventId {
+    pub reason: String,
+}
+
+impl Display for InvalidEventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Invalid event ID: {}", self.reason);
+    }
+}
+
+impl std::error::Error for InvalidEventId {}
+
+// A message offset before the first retained message or after the next one
+#[derive(Debug)]
+pub struct OffsetOutOfRange {
+    pub offset: u64,
+    pub first_offset: u64,
+    pub next_offset: u64,
+}
+
+impl Display for OffsetOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Offset {} is outside of the retained offsets {} to {}.", self.offset, self.first_offset, self.next_offset);
+    }
+}
+
+impl std::error::Error for OffsetOutOfRange {}
+
+// A commit that would move a consumer behind its committed position
+#[derive(Debug)]
+pub struct StaleEventId {
+    pub event_id: String,
+    pub committed: String,
+}
+
+impl Display for StaleEventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Event ID {} is behind the committed event ID {}.", self.event_id, self.committed);
+    }
+}
+
+impl std::error::Error for StaleEventId {}
+
+// A topic that doesn't exist
+#[derive(Debug)]
+pub struct UnknownTopic {
+    pub topic: String,
+}
+
+impl Display for UnknownTopic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Topic {} has not been created yet.", self.topic);
+    }
+}
+
+impl std::error::Error for UnknownTopic {}
+
+#[derive(Debug)]
+pub struct TopicExists {
+    pub topic: String,
+}
+
+impl Display for TopicExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Topic {} already exists.", self.topic);
+    }
+}
+
+impl std::error::Error for TopicExists {}
+
+// A producer or consumer token that doesn't match a registered key
+#[derive(Debug)]
+pub struct InvalidToken;
+
+impl Display for InvalidToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Unauthorized.");
+    }
+}
+
+impl std::error::Error for InvalidToken {}
+
+// A producer, consumer or credential ID that isn't the offset of a live registry record
+#[derive(Debug)]
+pub struct UnknownId {
+    pub id: u64,
+}
+
+impl Display for UnknownId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Nothing is registered with ID {}.", self.id);
+    }
+}
+
+impl std::error::Error for UnknownId {}
+
+// A valid token used on a topic it wasn't granted
+#[derive(Debug)]
+pub struct TopicForbidden {
+    pub topic: String,
+}
+
+impl Display for TopicForbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Token has no access to topic {}.", self.topic);
+    }
+}
+
+impl std::error::Error for TopicForbidden {}
+
+// An event whose content is larger than its topic accepts
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Event of {} bytes is larger than the maximum of {} bytes.", self.size, self.limit);
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+// A malformed name, token or setting
+#[derive(Debug)]
+pub struct InvalidInput {
+    pub reason: String,
+}
+
+impl Display for InvalidInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}", self.reason);
+    }
+}
+
+impl std::error::Error for InvalidInput {}
@@ -0,0 +1,715 @@
+#![allow(unused)]
+
+use std::io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read};
+use super::storage::{storage, OpenOptions, StorageFile};
+use anyhow::{Result, anyhow};
+use crate::subjects::topic::Topic;
+use super::errors::{InvalidEventId, OffsetOutOfRange};
+use super::topics::{get_topic_file, read_segment_header, next_record_position, read_record_timestamp};
+
+// Index layout: base offset of the log file (8 bytes) followed by entries of
+// relative message offset (8 bytes) + byte position (8 bytes).
+// Only one record per INDEX_INTERVAL bytes of log is indexed, the rest are found
+// by walking the length prefixes from the nearest entry.
+const INDEX_HEADER_LENGTH:u64 = 8;
+const INDEX_ENTRY_LENGTH:u64 = 16;
+const INDEX_INTERVAL:u64 = 4096;
+
+// Time index layout: entries of timestamp (8 bytes) + byte position (8 bytes), no header.
+// Entries are only added when the timestamp moves forward, so they stay sorted even
+// if the clock steps back.
+const TIME_INDEX_HEADER_LENGTH:u64 = 0;
+
+// Compaction map layout: record count (8 bytes) & length (8 bytes) of the log file
+// before it was first compacted, followed by an entry per kept record of its message
+// offset relative to the log file (8 bytes), byte position before compaction (8 bytes)
+// & byte position (8 bytes). Kept events keep their message offset & event ID, so the
+// ones handed out before compaction point at the same event, or at the next kept one
+// when theirs was dropped.
+const COMPACTION_MAP_HEADER_LENGTH:u64 = 16;
+const COMPACTION_MAP_ENTRY_LENGTH:u64 = 24;
+
+// Size index layout: byte position (8 bytes) up to which the raw lengths of a compressed
+// log file's records have been added up, followed by their total (8 bytes). Stats only
+// decode the records appended after the position.
+const SIZE_INDEX_LENGTH:usize = 16;
+
+fn index_path(topic: &str, log_file: &u64) -> String {
+    return format!("logs/{}/{}.index", topic, log_file);
+}
+
+fn time_index_path(topic: &str, log_file: &u64) -> String {
+    return format!("logs/{}/{}.timeindex", topic, log_file);
+}
+
+fn compaction_map_path(topic: &str, log_file: &u64) -> String {
+    return format!("logs/{}/{}.compacted", topic, log_file);
+}
+
+fn size_index_path(topic: &str, log_file: &u64) -> String {
+    return format!("logs/{}/{}.sizeindex", topic, log_file);
+}
+
+fn read_entry(reader: &mut BufReader<&StorageFile>, entry: u64) -> Result<(u64, u64)> {
+    return read_entry_at(reader, INDEX_HEADER_LENGTH, entry);
+}
+
+fn read_entry_at(reader: &mut BufReader<&StorageFile>, header_length: u64, entry: u64) -> Result<(u64, u64)> {
+    reader.seek(SeekFrom::Start(header_length + entry * INDEX_ENTRY_LENGTH))?;
+
+    let mut offset_buffer = [0u8; 8];
+    reader.read_exact(&mut offset_buffer)?;
+    let offset = u64::from_be_bytes(offset_buffer);
+
+    let mut position_buffer = [0u8; 8];
+    reader.read_exact(&mut position_buffer)?;
+    let position = u64::from_be_bytes(position_buffer);
+
+    return Ok((offset, position));
+}
+
+fn entry_count(index: &StorageFile) -> Result<u64> {
+    return entry_count_at(index, INDEX_HEADER_LENGTH);
+}
+
+fn entry_count_at(index: &StorageFile, header_length: u64) -> Result<u64> {
+    let length = index.len()?;
+    return Ok(length.saturating_sub(header_length) / INDEX_ENTRY_LENGTH);
+}
+
+fn read_base_offset(index: &StorageFile) -> Result<u64> {
+    let mut reader = BufReader::with_capacity(INDEX_HEADER_LENGTH as usize, index);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut base_offset_buffer = [0u8; 8];
+    reader.read_exact(&mut base_offset_buffer)?;
+    return Ok(u64::from_be_bytes(base_offset_buffer));
+}
+
+// Counts the records between two record boundaries of a log file
+fn count_records(file: &StorageFile, from: u64, to: u64) -> Result<u64> {
+    let header = read_segment_header(file)?;
+    let mut reader = BufReader::new(file);
+    let mut position = from;
+    let mut count:u64 = 0;
+    while position < to {
+        position = next_record_position(&mut reader, &header, position)?;
+        count += 1;
+    }
+    return Ok(count);
+}
+
+fn open_index(topic: &str, log_file: &u64) -> Result<StorageFile> {
+    let path = index_path(topic, log_file);
+    if !storage().exists(&path) {
+        rebuild_index(topic, log_file)?;
+    }
+    let index = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .open(path)?;
+    return Ok(index);
+}
+
+// Offset of the first message in the log file, continuing from the previous log file
+pub fn get_base_offset(topic: &str, log_file: &u64) -> Result<u64> {
+    let path = index_path(topic, log_file);
+    if storage().exists(&path) {
+        let index = OpenOptions::new().read(true).open(path)?;
+        return read_base_offset(&index);
+    }
+    if *log_file == 0 {
+        return Ok(0);
+    }
+    // Retention keeps the index of the first retained log file, so the offsets of
+    // later log files can always be counted from it
+    let previous_log_file = log_file - 1;
+    let previous_path = format!("logs/{}/{}", topic, previous_log_file);
+    if !storage().exists(&previous_path) {
+        return Err(anyhow!("Base offset of log file {} of topic {} is unknown, its index and the previous log file are gone.", log_file, topic));
+    }
+    let previous_base_offset = get_base_offset(topic, &previous_log_file)?;
+    let previous_count = get_record_count(topic, &previous_log_file)?;
+    return Ok(previous_base_offset + previous_count);
+}
+
+pub fn rebuild_index(topic: &str, log_file: &u64) -> Result<()> {
+    let base_offset = get_base_offset(topic, log_file)?;
+    return rebuild_index_from(topic, log_file, base_offset);
+}
+
+// Rebuilds the index keeping a known base offset, compacted log files hold fewer
+// messages than the offsets between them & the next log file
+pub fn rebuild_index_from(topic: &str, log_file: &u64, base_offset: u64) -> Result<()> {
+    let map = CompactionMap::open(topic, log_file)?;
+    let file = get_topic_file(topic, log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+    let mut reader = BufReader::new(&file);
+
+    let mut entries:Vec<u8> = Vec::new();
+    entries.extend_from_slice(&base_offset.to_be_bytes());
+
+    let mut position = header.length;
+    let mut offset:u64 = 0;
+    let mut last_indexed:Option<u64> = None;
+    while position < file_length {
+        if last_indexed.is_none_or(|last| position - last >= INDEX_INTERVAL) {
+            // Compacted records keep the offset they were written with
+            let offset = match &map {
+                Some(map) => map.entry(offset)?.offset,
+                None => offset,
+            };
+            entries.extend_from_slice(&offset.to_be_bytes());
+            entries.extend_from_slice(&position.to_be_bytes());
+            last_indexed = Some(position);
+        }
+        position = next_record_position(&mut reader, &header, position)?;
+        offset += 1;
+    }
+
+    storage().write(&index_path(topic, log_file), &entries)?;
+    return Ok(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionEntry {
+    pub offset: u64,
+    pub original_position: u64,
+    pub position: u64,
+}
+
+pub struct CompactionMap {
+    file: StorageFile,
+    pub record_count: u64,
+    pub length: u64,
+    pub entries: u64,
+}
+
+impl CompactionMap {
+    // None for log files that were never compacted
+    pub fn open(topic: &str, log_file: &u64) -> Result<Option<Self>> {
+        let path = compaction_map_path(topic, log_file);
+        if !storage().exists(&path) {
+            return Ok(None);
+        }
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mut reader = BufReader::with_capacity(COMPACTION_MAP_HEADER_LENGTH as usize, &file);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer)?;
+        let record_count = u64::from_be_bytes(buffer);
+        reader.read_exact(&mut buffer)?;
+        let length = u64::from_be_bytes(buffer);
+        let entries = file.len()?.saturating_sub(COMPACTION_MAP_HEADER_LENGTH) / COMPACTION_MAP_ENTRY_LENGTH;
+        drop(reader);
+        return Ok(Some(CompactionMap { file, record_count, length, entries }));
+    }
+
+    // Position a record had before the log file was compacted
+    pub fn original_position(&self, position: u64) -> Result<u64> {
+        let entry = self.find(position, |entry| entry.position)?;
+        return Ok(entry.map(|entry| entry.original_position).unwrap_or(self.length));
+    }
+
+    pub fn entry(&self, entry: u64) -> Result<CompactionEntry> {
+        let mut reader = BufReader::with_capacity(COMPACTION_MAP_ENTRY_LENGTH as usize, &self.file);
+        reader.seek(SeekFrom::Start(COMPACTION_MAP_HEADER_LENGTH + entry * COMPACTION_MAP_ENTRY_LENGTH))?;
+        let mut fields = [0u64; 3];
+        for field in fields.iter_mut() {
+            let mut buffer = [0u8; 8];
+            reader.read_exact(&mut buffer)?;
+            *field = u64::from_be_bytes(buffer);
+        }
+        return Ok(CompactionEntry { offset: fields[0], original_position: fields[1], position: fields[2] });
+    }
+
+    // First entry whose field is at or after the value, entries are in order of
+    // every field
+    pub fn find(&self, value: u64, field: fn(&CompactionEntry) -> u64) -> Result<Option<CompactionEntry>> {
+        let mut low:u64 = 0;
+        let mut high:u64 = self.entries;
+        while low < high {
+            let middle = (low + high) / 2;
+            if field(&self.entry(middle)?) < value {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        if low == self.entries {
+            return Ok(None);
+        }
+        return Ok(Some(self.entry(low)?));
+    }
+}
+
+pub fn write_compaction_map(topic: &str, log_file: &u64, record_count: u64, length: u64, entries: &[CompactionEntry]) -> Result<()> {
+    let mut contents:Vec<u8> = Vec::with_capacity((COMPACTION_MAP_HEADER_LENGTH + entries.len() as u64 * COMPACTION_MAP_ENTRY_LENGTH) as usize);
+    contents.extend_from_slice(&record_count.to_be_bytes());
+    contents.extend_from_slice(&length.to_be_bytes());
+    for entry in entries {
+        contents.extend_from_slice(&entry.offset.to_be_bytes());
+        contents.extend_from_slice(&entry.original_position.to_be_bytes());
+        contents.extend_from_slice(&entry.position.to_be_bytes());
+    }
+    storage().write(&compaction_map_path(topic, log_file), &contents)?;
+    return Ok(());
+}
+
+pub fn delete_compaction_map(topic: &str, log_file: &u64) -> Result<()> {
+    let path = compaction_map_path(topic, log_file);
+    if storage().exists(&path) {
+        storage().remove_file(&path)?;
+    }
+    return Ok(());
+}
+
+pub fn delete_index(topic: &str, log_file: &u64) -> Result<()> {
+    for path in [index_path(topic, log_file), time_index_path(topic, log_file), size_index_path(topic, log_file)] {
+        if storage().exists(&path) {
+            storage().remove_file(&path)?;
+        }
+    }
+    return Ok(());
+}
+
+// Byte position & raw length of the records before it, none until sizes were first
+// added up or when the size index was cut short
+pub fn read_size_index(topic: &str, log_file: &u64) -> Result<Option<(u64, u64)>> {
+    let path = size_index_path(topic, log_file);
+    if !storage().exists(&path) {
+        return Ok(None);
+    }
+    let mut file = OpenOptions::new()
+                    .read(true)
+                    .open(path)?;
+    let mut buffer = [0u8; SIZE_INDEX_LENGTH];
+    if file.read_exact(&mut buffer).is_err() {
+        return Ok(None);
+    }
+    let position = u64::from_be_bytes(buffer[0..8].try_into()?);
+    let raw_length = u64::from_be_bytes(buffer[8..16].try_into()?);
+    return Ok(Some((position, raw_length)));
+}
+
+pub fn write_size_index(topic: &str, log_file: &u64, position: u64, raw_length: u64) -> Result<()> {
+    let mut buffer = Vec::with_capacity(SIZE_INDEX_LENGTH);
+    buffer.extend_from_slice(&position.to_be_bytes());
+    buffer.extend_from_slice(&raw_length.to_be_bytes());
+    storage().write(&size_index_path(topic, log_file), &buffer)?;
+    return Ok(());
+}
+
+// Called after a record has been appended at the given byte position
+pub fn index_record(topic: &str, log_file: &u64, position: u64) -> Result<()> {
+    let index = open_index(topic, log_file)?;
+    let entries = entry_count(&index)?;
+
+    let offset = if entries == 0 {
+        0
+    } else {
+        let mut reader = BufReader::new(&index);
+        let (last_offset, last_position) = read_entry(&mut reader, entries - 1)?;
+
+        // Already covered when the index was just rebuilt, or not due yet
+        if last_position >= position || position - last_position < INDEX_INTERVAL {
+            return Ok(());
+        }
+        let file = get_topic_file(topic, log_file)?;
+        last_offset + count_records(&file, last_position, position)?
+    };
+
+    let mut writer = BufWriter::with_capacity(INDEX_ENTRY_LENGTH as usize, &index);
+    writer.write_all(&offset.to_be_bytes())?;
+    writer.write_all(&position.to_be_bytes())?;
+    writer.flush()?;
+
+    return Ok(());
+}
+
+// Messages the log file was given offsets for, including ones compacted away
+pub fn get_record_count(topic: &str, log_file: &u64) -> Result<u64> {
+    if let Some(map) = CompactionMap::open(topic, log_file)? {
+        return Ok(map.record_count);
+    }
+    let index = open_index(topic, log_file)?;
+    let entries = entry_count(&index)?;
+    if entries == 0 {
+        return Ok(0);
+    }
+
+    let mut reader = BufReader::new(&index);
+    let (last_offset, last_position) = read_entry(&mut reader, entries - 1)?;
+
+    let file = get_topic_file(topic, log_file)?;
+    let file_length = file.len()?;
+    return Ok(last_offset + count_records(&file, last_position, file_length)?);
+}
+
+// Events the log file still holds
+pub fn get_event_count(topic: &str, log_file: &u64) -> Result<u64> {
+    if let Some(map) = CompactionMap::open(topic, log_file)? {
+        return Ok(map.entries);
+    }
+    return get_record_count(topic, log_file);
+}
+
+// Builds the index of a log file when it doesn't have one yet
+pub fn ensure_index(topic: &str, log_file: &u64) -> Result<()> {
+    open_index(topic, log_file)?;
+    return Ok(());
+}
+
+// Offset of the first retained message and the offset the next message will get
+pub fn get_offset_range(topic: &Topic) -> Result<(u64, u64)> {
+    let first_offset = get_base_offset(&topic.name, &topic.first_log_file)?;
+    let next_offset = get_base_offset(&topic.name, &topic.curr_log_file)? + get_record_count(&topic.name, &topic.curr_log_file)?;
+    return Ok((first_offset, next_offset));
+}
+
+// Checks that the log file & byte position of an event ID is the start of a record
+// (or the end of the log file) within the retained log files. Returns the byte
+// position in the log file, which moves once the log file is compacted.
+pub fn resolve_position(topic: &Topic, log_file: u64, position: u64) -> Result<u64> {
+    let invalid = |reason: String| InvalidEventId { reason };
+    if log_file < topic.first_log_file || log_file > topic.curr_log_file {
+        return Err(invalid(format!("log file {} is outside of the retained log files {} to {}.", log_file, topic.first_log_file, topic.curr_log_file)).into());
+    }
+
+    let file = get_topic_file(&topic.name, &log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+
+    // Event IDs of compacted log files hold the position from before compaction
+    if let Some(map) = CompactionMap::open(&topic.name, &log_file)? {
+        if position < header.length || position > map.length {
+            return Err(invalid(format!("position {} is outside of log file {}.", position, log_file)).into());
+        }
+        let entry = map.find(position, |entry| entry.original_position)?;
+        return Ok(entry.map(|entry| entry.position).unwrap_or(file_length));
+    }
+
+    if position == header.length || position == file_length {
+        return Ok(position);
+    }
+    if position < header.length || position > file_length {
+        return Err(invalid(format!("position {} is outside of log file {}.", position, log_file)).into());
+    }
+
+    // Walk from the last indexed record at or before the position
+    let index = open_index(&topic.name, &log_file)?;
+    let entries = entry_count(&index)?;
+    let mut reader = BufReader::new(&index);
+    let mut record_position = header.length;
+    let mut low:u64 = 0;
+    let mut high:u64 = entries;
+    while low < high {
+        let middle = (low + high) / 2;
+        let (_, entry_position) = read_entry(&mut reader, middle)?;
+        if entry_position <= position {
+            record_position = entry_position;
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    let mut file_reader = BufReader::new(&file);
+    while record_position < position {
+        record_position = next_record_position(&mut file_reader, &header, record_position)?;
+    }
+    if record_position != position {
+        return Err(invalid(format!("position {} is not a record boundary in log file {}.", position, log_file)).into());
+    }
+    return Ok(position);
+}
+
+// The byte position an event ID holds for a position in the log file, the one from
+// before compaction for compacted log files
+pub fn original_position(topic: &str, log_file: u64, position: u64) -> Result<u64> {
+    return match CompactionMap::open(topic, &log_file)? {
+        Some(map) => map.original_position(position),
+        None => Ok(position),
+    };
+}
+
+// Finds the log file & byte position of a message offset. The offset right after
+// the last message resolves to the end of the current log file.
+pub fn find_offset(topic: &Topic, offset: u64) -> Result<(u64, u64)> {
+    let (first_offset, next_offset) = get_offset_range(topic)?;
+    if offset < first_offset || offset > next_offset {
+        return Err(OffsetOutOfRange { offset, first_offset, next_offset }.into());
+    }
+
+    let mut log_file = topic.curr_log_file;
+    while log_file > topic.first_log_file && get_base_offset(&topic.name, &log_file)? > offset {
+        log_file -= 1;
+    }
+    let relative_offset = offset - get_base_offset(&topic.name, &log_file)?;
+
+    // Offsets compacted away resolve to the next retained message
+    if log_file < topic.curr_log_file && relative_offset >= get_record_count(&topic.name, &log_file)? {
+        return first_event_after(topic, log_file);
+    }
+
+    // Offsets compacted away inside the log file resolve to the next kept message
+    if let Some(map) = CompactionMap::open(&topic.name, &log_file)? {
+        if let Some(entry) = map.find(relative_offset, |entry| entry.offset)? {
+            return Ok((log_file, entry.position));
+        }
+        return first_event_after(topic, log_file);
+    }
+
+    let index = open_index(&topic.name, &log_file)?;
+    let entries = entry_count(&index)?;
+    let file = get_topic_file(&topic.name, &log_file)?;
+    if entries == 0 {
+        return Ok((log_file, read_segment_header(&file)?.length));
+    }
+
+    // Binary search for the last entry at or before the offset
+    let mut reader = BufReader::new(&index);
+    let mut low:u64 = 0;
+    let mut high:u64 = entries - 1;
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        let (entry_offset, _) = read_entry(&mut reader, middle)?;
+        if entry_offset <= relative_offset {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    let (mut entry_offset, mut position) = read_entry(&mut reader, low)?;
+
+    let header = read_segment_header(&file)?;
+    let mut file_reader = BufReader::new(&file);
+    while entry_offset < relative_offset {
+        position = next_record_position(&mut file_reader, &header, position)?;
+        entry_offset += 1;
+    }
+
+    return Ok((log_file, position));
+}
+
+// Start of the first log file after the given one that still holds events, or the
+// end of the current log file. Compaction can leave log files without events.
+fn first_event_after(topic: &Topic, log_file: u64) -> Result<(u64, u64)> {
+    let mut log_file = log_file + 1;
+    loop {
+        let file = get_topic_file(&topic.name, &log_file)?;
+        let header_length = read_segment_header(&file)?.length;
+        if log_file == topic.curr_log_file || file.len()? > header_length {
+            return Ok((log_file, header_length));
+        }
+        log_file += 1;
+    }
+}
+
+fn open_time_index(topic: &str, log_file: &u64) -> Result<StorageFile> {
+    let path = time_index_path(topic, log_file);
+    if !storage().exists(&path) {
+        rebuild_time_index(topic, log_file)?;
+    }
+    let index = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .open(path)?;
+    return Ok(index);
+}
+
+pub fn rebuild_time_index(topic: &str, log_file: &u64) -> Result<()> {
+    let file = get_topic_file(topic, log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+    let mut reader = BufReader::new(&file);
+
+    let mut entries:Vec<u8> = Vec::new();
+    let mut position = header.length;
+    let mut last_entry:Option<(u64, u64)> = None;
+    while position < file_length {
+        let timestamp = read_record_timestamp(&mut reader, &header, position)?;
+        if is_time_index_due(last_entry, timestamp, position) {
+            entries.extend_from_slice(&timestamp.to_be_bytes());
+            entries.extend_from_slice(&position.to_be_bytes());
+            last_entry = Some((timestamp, position));
+        }
+        position = next_record_position(&mut reader, &header, position)?;
+    }
+
+    storage().write(&time_index_path(topic, log_file), &entries)?;
+    return Ok(());
+}
+
+fn is_time_index_due(last_entry: Option<(u64, u64)>, timestamp: u64, position: u64) -> bool {
+    match last_entry {
+        None => return true,
+        Some((last_timestamp, last_position)) => return timestamp > last_timestamp && position - last_position >= INDEX_INTERVAL,
+    }
+}
+
+// Called after a record has been appended at the given byte position
+pub fn time_index_record(topic: &str, log_file: &u64, position: u64, timestamp: u64) -> Result<()> {
+    let index = open_time_index(topic, log_file)?;
+    let entries = entry_count_at(&index, TIME_INDEX_HEADER_LENGTH)?;
+
+    let last_entry = if entries == 0 {
+        None
+    } else {
+        let mut reader = BufReader::new(&index);
+        Some(read_entry_at(&mut reader, TIME_INDEX_HEADER_LENGTH, entries - 1)?)
+    };
+    if last_entry.is_some_and(|(_, last_position)| last_position >= position) || !is_time_index_due(last_entry, timestamp, position) {
+        return Ok(());
+    }
+
+    let mut writer = BufWriter::with_capacity(INDEX_ENTRY_LENGTH as usize, &index);
+    writer.write_all(&timestamp.to_be_bytes())?;
+    writer.write_all(&position.to_be_bytes())?;
+    writer.flush()?;
+
+    return Ok(());
+}
+
+// Finds the log file & byte position of the first event at or after the timestamp.
+// Resolves to the end of the current log file when every event is older.
+pub fn find_timestamp(topic: &Topic, timestamp: u64) -> Result<(u64, u64)> {
+    for log_file in topic.first_log_file..=topic.curr_log_file {
+        let index = open_time_index(&topic.name, &log_file)?;
+        let entries = entry_count_at(&index, TIME_INDEX_HEADER_LENGTH)?;
+        let file = get_topic_file(&topic.name, &log_file)?;
+        let file_length = file.len()?;
+        let header = read_segment_header(&file)?;
+
+        // Binary search for the last entry before the timestamp & scan from there
+        let mut position = header.length;
+        let mut index_reader = BufReader::new(&index);
+        let mut low:u64 = 0;
+        let mut high:u64 = entries;
+        while low < high {
+            let middle = (low + high) / 2;
+            let (entry_timestamp, entry_position) = read_entry_at(&mut index_reader, TIME_INDEX_HEADER_LENGTH, middle)?;
+            if entry_timestamp < timestamp {
+                position = entry_position;
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let mut reader = BufReader::new(&file);
+        while position < file_length {
+            if read_record_timestamp(&mut reader, &header, position)? >= timestamp {
+                return Ok((log_file, position));
+            }
+            position = next_record_position(&mut reader, &header, position)?;
+        }
+
+        if log_file == topic.curr_log_file {
+            return Ok((log_file, file_length));
+        }
+    }
+    return Err(anyhow!("Topic {} has no log files.", topic.name));
+}
+
+// Timestamp of the newest event in the log file, None when it has no events
+pub fn get_last_timestamp(topic: &str, log_file: &u64) -> Result<Option<u64>> {
+    let index = open_time_index(topic, log_file)?;
+    let entries = entry_count_at(&index, TIME_INDEX_HEADER_LENGTH)?;
+    let file = get_topic_file(topic, log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+
+    // Events after the last entry aren't indexed yet, scan them from there
+    let mut last_timestamp:Option<u64> = None;
+    let mut position = header.length;
+    if entries > 0 {
+        let mut index_reader = BufReader::new(&index);
+        let (entry_timestamp, entry_position) = read_entry_at(&mut index_reader, TIME_INDEX_HEADER_LENGTH, entries - 1)?;
+        last_timestamp = Some(entry_timestamp);
+        position = entry_position;
+    }
+
+    let mut reader = BufReader::new(&file);
+    while position < file_length {
+        let timestamp = read_record_timestamp(&mut reader, &header, position)?;
+        last_timestamp = Some(last_timestamp.map_or(timestamp, |last| last.max(timestamp)));
+        position = next_record_position(&mut reader, &header, position)?;
+    }
+
+    return Ok(last_timestamp);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::test_storage;
+    use super::super::topics::{write, read_at};
+    use crate::subjects::event::Event;
+
+    const EVENTS:u64 = 500;
+
+    fn content(event: u64) -> Vec<u8> {
+        return format!("event {} {}", event, "x".repeat(100)).into_bytes();
+    }
+
+    // Events spread over several log files, event n written at (n + 1) seconds
+    fn indexed_topic(name: &str) -> Topic {
+        let topic = Topic::new(name.to_string()).unwrap();
+        topic.configure("segment_bytes", "20000").unwrap();
+        for event in 0..EVENTS {
+            let mut event_data = Event::new(content(event), None, Vec::new());
+            event_data.timestamp = (event + 1) * 1000;
+            write(&topic.name, &event_data, false).unwrap();
+        }
+        return Topic::hydrate(name).unwrap();
+    }
+
+    #[test]
+    fn finds_offsets_through_sparse_indexes() {
+        let _storage = test_storage();
+        let topic = indexed_topic("offset-lookup");
+        assert!(topic.curr_log_file > 1);
+        assert!(entry_count(&open_index(&topic.name, &0).unwrap()).unwrap() > 1);
+        assert_eq!(get_offset_range(&topic).unwrap(), (0, EVENTS));
+
+        for offset in [0, 1, 37, 150, 151, 300, EVENTS - 1] {
+            let (log_file, position) = find_offset(&topic, offset).unwrap();
+            assert_eq!(read_at(&topic.name, &log_file, position).unwrap().content, content(offset));
+        }
+
+        // The next offset is the end of the current log file
+        let (log_file, position) = find_offset(&topic, EVENTS).unwrap();
+        assert_eq!(log_file, topic.curr_log_file);
+        assert_eq!(position, get_topic_file(&topic.name, &log_file).unwrap().len().unwrap());
+    }
+
+    #[test]
+    fn rejects_offsets_out_of_range() {
+        let _storage = test_storage();
+        let topic = indexed_topic("offset-range");
+        let error = find_offset(&topic, EVENTS + 1).err().unwrap();
+        let range = error.downcast_ref::<OffsetOutOfRange>().unwrap();
+        assert_eq!((range.offset, range.first_offset, range.next_offset), (EVENTS + 1, 0, EVENTS));
+    }
+
+    #[test]
+    fn finds_timestamps_through_time_indexes() {
+        let _storage = test_storage();
+        let topic = indexed_topic("timestamp-lookup");
+        assert!(entry_count_at(&open_time_index(&topic.name, &0).unwrap(), TIME_INDEX_HEADER_LENGTH).unwrap() > 1);
+
+        for event in [0, 1, 37, 150, 151, 300, EVENTS - 1] {
+            // Exact timestamps & ones between events find the first event at or after them
+            for timestamp in [(event + 1) * 1000, event * 1000 + 1] {
+                let (log_file, position) = find_timestamp(&topic, timestamp).unwrap();
+                assert_eq!(read_at(&topic.name, &log_file, position).unwrap().content, content(event));
+            }
+        }
+
+        let (log_file, position) = find_timestamp(&topic, (EVENTS + 1) * 1000).unwrap();
+        assert_eq!(log_file, topic.curr_log_file);
+        assert_eq!(position, get_topic_file(&topic.name, &log_file).unwrap().len().unwrap());
+        assert_eq!(get_last_timestamp(&topic.name, &topic.curr_log_file).unwrap(), Some(EVENTS * 1000));
+    }
+}
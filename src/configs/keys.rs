@@ -0,0 +1,69 @@
+#![allow(unused)]
+
+use std::io::{Write, Seek, SeekFrom, Read};
+use crate::subjects::keys::{hash_key, is_plain_key, KeyExpiry, KEY_LENGTH, KEY_EXPIRY_LENGTH};
+use super::storage::{storage, lock_registries, OpenOptions};
+use super::{producers::list_producers, consumers::list_consumers, credentials::list_credentials};
+use anyhow::Result;
+
+// Registries written before keys were hashed hold plain keys. Those keep working
+// until they are hashed in place, which leaves record offsets & tokens as they are.
+
+// Returns the number of keys hashed across all registries
+pub fn hash_plain_keys() -> Result<u64> {
+    let mut hashed = 0;
+
+    let producers = list_producers()?.into_iter().map(|producer| (producer.offset, producer.stored_key)).collect();
+    hashed += hash_registry_keys("configs/producers", producers)?;
+
+    let consumers = list_consumers()?.into_iter().map(|consumer| (consumer.offset, consumer.stored_key)).collect();
+    hashed += hash_registry_keys("configs/consumers", consumers)?;
+
+    let credentials = list_credentials()?.into_iter().map(|credential| (credential.offset, credential.stored_key)).collect();
+    hashed += hash_registry_keys("configs/credentials", credentials)?;
+
+    return Ok(hashed);
+}
+
+fn hash_registry_keys(path: &str, stored_keys: Vec<(u64, [u8; KEY_LENGTH])>) -> Result<u64> {
+    let plain_keys = stored_keys.into_iter().filter(|(_, stored_key)| is_plain_key(stored_key)).collect::<Vec<(u64, [u8; KEY_LENGTH])>>();
+    if plain_keys.is_empty() {
+        return Ok(0);
+    }
+
+    let _lock = lock_registries(true)?;
+    let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+
+    let mut hashed = 0;
+    for (offset, plain_key) in plain_keys {
+        // Keys rerolled or deleted since listing are left alone
+        let mut stored_key = [0u8; KEY_LENGTH];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut stored_key)?;
+        if stored_key != plain_key {
+            continue;
+        }
+
+        // Plain keys are ASCII UUIDs
+        let key = std::str::from_utf8(&plain_key)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&hash_key(key))?;
+        hashed += 1;
+    }
+    file.flush()?;
+
+    return Ok(hashed);
+}
+
+// Reads the key expiry at the end of records that are flagged as having one
+pub fn read_key_expiry<R: Read>(reader: &mut R, has_expiry: bool) -> Result<Option<KeyExpiry>> {
+    if !has_expiry {
+        return Ok(None);
+    }
+    let mut expiry_buffer = [0u8; KEY_EXPIRY_LENGTH as usize];
+    reader.read_exact(&mut expiry_buffer)?;
+    return Ok(Some(KeyExpiry::from_bytes(&expiry_buffer)));
+}
@@ -1,3 +1,57 @@
 pub mod topics;
 pub mod producers;
 pub mod consumers;
+pub mod errors;
+pub mod indexes;
+pub mod compaction;
+pub mod storage;
+pub mod credentials;
+pub mod keys;
+
+use std::{env, fs, sync::OnceLock};
+use anyhow::{Result, anyhow};
+
+const DEFAULT_DATA_DIR:&str = "sailfish";
+
+static DATA_DIR: OnceLock<String> = OnceLock::new();
+
+// Picks the data directory, in order of precedence: the --data-dir flag, the
+// SAILFISH_DATA_DIR environment variable, data_dir= in the config file given by
+// --config or SAILFISH_CONFIG, and finally ./sailfish
+pub fn init_data_dir(args: &[String]) -> Result<()> {
+    let mut data_dir = get_flag(args, "--data-dir")?.or(env::var("SAILFISH_DATA_DIR").ok());
+
+    if data_dir.is_none() {
+        if let Some(config) = get_flag(args, "--config")?.or(env::var("SAILFISH_CONFIG").ok()) {
+            let contents = fs::read_to_string(&config).map_err(|e| anyhow!("Unable to read config file {}: {}", config, e))?;
+            for line in contents.lines() {
+                if let Some(("data_dir", value)) = line.split_once('=').map(|(key, value)| (key.trim(), value.trim())) {
+                    data_dir = Some(value.to_owned());
+                }
+            }
+        }
+    }
+
+    let data_dir = data_dir.unwrap_or(DEFAULT_DATA_DIR.to_owned());
+    let data_dir = data_dir.trim_end_matches('/');
+    if data_dir.is_empty() {
+        return Err(anyhow!("Invalid data directory."));
+    }
+    let _ = DATA_DIR.set(data_dir.to_owned());
+    return Ok(());
+}
+
+// Value following a flag, e.g. --data-dir /var/lib/sailfish
+pub fn get_flag(args: &[String], flag: &str) -> Result<Option<String>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => return Ok(Some(value.to_owned())),
+            None => return Err(anyhow!("Missing value for {}.", flag)),
+        },
+        None => return Ok(None),
+    }
+}
+
+pub fn data_dir() -> &'static str {
+    return DATA_DIR.get().map(|dir| dir.as_str()).unwrap_or(DEFAULT_DATA_DIR);
+}
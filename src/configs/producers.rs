@@ -1,36 +1,39 @@
 #![allow(unused)]
 
-use std::{path::Path, fs::{self, File, OpenOptions}, io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read}};
-use crate::subjects::{producer::Producer, keys::generate_key};
+use std::io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read};
+use crate::subjects::{producer::Producer, event::now, keys::{generate_key, hash_key, is_deleted_key, KeyExpiry, KEY_LENGTH, KEY_EXPIRY_FLAG, KEY_EXPIRY_LENGTH}};
+use super::storage::{storage, lock_registries, OpenOptions, StorageFile};
+use super::errors::InvalidToken;
+use super::keys::read_key_expiry;
 use anyhow::Result;
 
 fn create_configs_dir() -> Result<()>{
-    let path = Path::new("sailfish/configs");
-    if !path.exists() {
-        fs::create_dir_all(path)?;
+    let path = "configs";
+    if !storage().exists(path) {
+        storage().create_dir_all(path)?;
     }
     return Ok(());
 }
 
-pub fn create_producers_file() -> Result<File> {
-    let path = format!("sailfish/configs/producers");
-    let path = Path::new(&path);
+pub fn create_producers_file() -> Result<StorageFile> {
+    let path = "configs/producers";
     let file = OpenOptions::new()
                     .write(true)
                     .create(true)
+                    .truncate(false)
                     .open(path)?;
     return Ok(file);
 }
 
 pub fn producers_exists() -> bool {
-    let path = format!("sailfish/configs/producers");
-    let path = Path::new(&path);
-    return path.exists();
+    let path = "configs/producers";
+    return storage().exists(path);
 }
 
 pub fn add_producer_to_config(producer: &mut Producer) -> Result<()> {
+    let _lock = lock_registries(true)?;
     create_configs_dir()?;
-    let path = Path::new("sailfish/configs/producers");
+    let path = "configs/producers";
     let file =  OpenOptions::new()
                     .append(true)
                     .open(path)?;
@@ -39,56 +42,78 @@ pub fn add_producer_to_config(producer: &mut Producer) -> Result<()> {
     producer.offset = writer.seek(SeekFrom::End(0))?;
     writer.seek(SeekFrom::Start(producer.offset))?;
 
-    // We don't need to track the length of the key because it is a UUIDv4
-    // It will always be 36 bytes
-    writer.write_all(producer.key.as_bytes())?;
+    // Only the salted hash of the key is stored, always 36 bytes
+    producer.stored_key = hash_key(&producer.key);
+    writer.write_all(&producer.stored_key)?;
 
-    // Write topic & string length
+    // Write topic & string length, flagged as followed by the key expiry
     let topic_bytes = producer.topic.as_bytes();
     let topic_length = topic_bytes.len() as u64;
-    writer.write_all(&topic_length.to_be_bytes())?;
+    writer.write_all(&(topic_length | KEY_EXPIRY_FLAG).to_be_bytes())?;
     writer.write_all(topic_bytes)?;
 
+    let expiry = *producer.expiry.get_or_insert(KeyExpiry::new(None));
+    writer.write_all(&expiry.to_bytes())?;
+
     writer.flush()?;
 
     return Ok(());
 }
 
-pub fn get_producer(offset: u64) -> Result<Producer> {
-    let path = Path::new("sailfish/configs/producers");
-    let file =  OpenOptions::new()
-                    .read(true)
-                    .open(path)?;
-
-    let mut reader = BufReader::new(&file);
-    reader.seek(SeekFrom::Start(offset))?;
-
+// Returns the producer & the length of its record
+fn read_producer(reader: &mut BufReader<&StorageFile>, offset: u64, file_length: u64) -> Result<(Producer, u64)> {
     // Read key
-    let mut key_buffer = [0u8; 36];
-    reader.read_exact(&mut key_buffer)?;
-    let key = std::str::from_utf8(&key_buffer).unwrap();
+    let mut stored_key = [0u8; KEY_LENGTH];
+    reader.read_exact(&mut stored_key)?;
 
     // Read topic length
     let mut topic_length_buffer = [0u8; 8];
     reader.read_exact(&mut topic_length_buffer)?;
     let topic_length:u64 = u64::from_be_bytes(topic_length_buffer);
+    let has_expiry = topic_length & KEY_EXPIRY_FLAG != 0;
+    let topic_length = topic_length & !KEY_EXPIRY_FLAG;
+
+    // Tokens with an offset inside another record read garbage lengths
+    if topic_length > file_length.saturating_sub(offset + 44) {
+        return Err(InvalidToken.into());
+    }
 
     // Read topic
     let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
     reader.read_exact(&mut topic_buffer[..])?;
-    let topic = std::str::from_utf8(&topic_buffer).unwrap();
+    let topic = std::str::from_utf8(&topic_buffer).map_err(|_| InvalidToken)?;
+
+    let expiry = read_key_expiry(reader, has_expiry)?;
 
     let producer = Producer{
         topic: topic.to_owned(),
         offset,
-        key: key.to_owned(),
+        key: String::new(),
+        stored_key,
+        expiry,
     };
+    let length = 36 + 8 + topic_length + if has_expiry { KEY_EXPIRY_LENGTH } else { 0 };
+
+    return Ok((producer, length));
+}
+
+pub fn get_producer(offset: u64) -> Result<Producer> {
+    let _lock = lock_registries(false)?;
+    let path = "configs/producers";
+    let file =  OpenOptions::new()
+                    .read(true)
+                    .open(path)?;
+
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(offset))?;
+    let (producer, _) = read_producer(&mut reader, offset, file.len()?)?;
 
     return Ok(producer);
 }
 
 pub fn delete_producer(producer: &Producer) -> Result<()> {
-    let path = Path::new("sailfish/configs/producers");
+    let _lock = lock_registries(true)?;
+    let path = "configs/producers";
     let file =  OpenOptions::new()
                     .write(true)
                     .open(path)?;
@@ -96,7 +121,7 @@ pub fn delete_producer(producer: &Producer) -> Result<()> {
     let mut writer = BufWriter::with_capacity(36, &file);
     writer.seek(SeekFrom::Start(producer.offset))?;
 
-    // Overwrite key with null bytes (36)
+    // Overwrite key with null bytes (36), the previous key goes with it
     let null_bytes = [0u8; 36];
     writer.write_all(&null_bytes)?;
 
@@ -105,23 +130,43 @@ pub fn delete_producer(producer: &Producer) -> Result<()> {
     return Ok(());
 }
 
-pub fn reroll_producer_key(producer: &Producer) -> Result<String> {
-    let path = Path::new("sailfish/configs/producers");
+// Returns the new key with the record's new stored key & expiry
+pub fn reroll_producer_key(producer: &Producer, grace_ms: u64, expires_at: Option<u64>) -> Result<(String, [u8; KEY_LENGTH], Option<KeyExpiry>)> {
+    let _lock = lock_registries(true)?;
+    let path = "configs/producers";
     let file =  OpenOptions::new()
+                    .read(true)
                     .write(true)
                     .open(path)?;
 
-    let mut writer = BufWriter::with_capacity(36, &file);
-    writer.seek(SeekFrom::Start(producer.offset))?;
+    // The key being replaced is read under the lock, in case of a concurrent reroll
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(producer.offset))?;
+    let (current, length) = read_producer(&mut reader, producer.offset, file.len()?)?;
+    drop(reader);
 
     let new_key = generate_key();
-    writer.write_all(new_key.as_bytes())?;
+    let stored_key = hash_key(&new_key);
+    let expiry = current.expiry.map(|expiry| expiry.reroll(&current.stored_key, grace_ms, expires_at, now()));
+
+    let mut writer = BufWriter::with_capacity(36, &file);
+    writer.seek(SeekFrom::Start(producer.offset))?;
+    writer.write_all(&stored_key)?;
+    if let Some(expiry) = &expiry {
+        writer.seek(SeekFrom::Start(producer.offset + length - KEY_EXPIRY_LENGTH))?;
+        writer.write_all(&expiry.to_bytes())?;
+    }
+    writer.flush()?;
 
-    return Ok(new_key);
+    return Ok((new_key, stored_key, expiry));
 }
 
-pub fn list_producers() -> Result<()> {
-    let path = Path::new("sailfish/configs/producers");
+pub fn list_producers() -> Result<Vec<Producer>> {
+    if !producers_exists() {
+        return Ok(Vec::new());
+    }
+    let _lock = lock_registries(false)?;
+    let path = "configs/producers";
     let file =  OpenOptions::new()
                     .read(true)
                     .open(path)?;
@@ -129,41 +174,18 @@ pub fn list_producers() -> Result<()> {
     let mut reader = BufReader::new(&file);
     reader.seek(SeekFrom::Start(0))?;
 
-    let total_bytes = file.metadata()?.len();
+    let total_bytes = file.len()?;
+    let mut producers:Vec<Producer> = Vec::new();
     let mut bytes_read = 0;
 
-    loop {
-        if bytes_read == total_bytes {
-            break;
+    while bytes_read < total_bytes {
+        let (producer, length) = read_producer(&mut reader, bytes_read, total_bytes)?;
+        // Only the hash of the key is known, the key itself stays empty
+        if !is_deleted_key(&producer.stored_key) {
+            producers.push(producer);
         }
-
-        // Read key
-        let mut key_buffer = [0u8; 36];
-        reader.read_exact(&mut key_buffer)?;
-        let key = std::str::from_utf8(&key_buffer).unwrap_or("");
-        let key = String::from(key);
-
-        // Read topic length
-        let mut topic_length_buffer = [0u8; 8];
-        reader.read_exact(&mut topic_length_buffer)?;
-        let topic_length = u64::from_be_bytes(topic_length_buffer);
-
-        // Read topic
-        let mut topic_buffer:Vec<u8> = vec![0; topic_length as usize];
-        reader.read_exact(&mut topic_buffer[..])?;
-        let topic = std::str::from_utf8(&topic_buffer).unwrap();
-
-        if !key.escape_default().to_string().contains("\\u{0}") {
-            let producer = Producer{
-                topic: topic.to_owned(),
-                offset: bytes_read,
-                key: key.to_owned(),
-            };
-            println!("{}", producer);
-        }
-
-        bytes_read += 36 + 8 + topic_length;
+        bytes_read += length;
     }
 
-    return Ok(());
+    return Ok(producers);
 }
@@ -0,0 +1,561 @@
+#![allow(unused)]
+
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, Read, Write, Seek, SeekFrom}, path::Path, sync::{Arc, Condvar, Mutex, RwLock, OnceLock}};
+use anyhow::{Result, anyhow};
+use super::data_dir;
+
+// Every log, index, settings & registry file is read and written through a Storage.
+// Paths are relative to the data directory, like "logs/orders/0" or "configs/topics".
+pub trait Storage: Send + Sync {
+    fn open(&self, path: &str, options: &OpenOptions) -> Result<StorageFile>;
+    fn exists(&self, path: &str) -> bool;
+    fn create_dir_all(&self, path: &str) -> Result<()>;
+    fn remove_file(&self, path: &str) -> Result<()>;
+    fn remove_dir_all(&self, path: &str) -> Result<()>;
+    // Replaces the destination file if it exists
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    // Advisory lock held until the returned guard is dropped. Shared locks only
+    // exclude exclusive ones. Paths in missing directories have nothing to protect
+    // and aren't locked.
+    fn lock(&self, path: &str, exclusive: bool) -> Result<StorageLock>;
+
+    fn read_to_string(&self, path: &str) -> Result<String> {
+        let mut file = self.open(path, OpenOptions::new().read(true))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        return Ok(contents);
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<()> {
+        let mut file = self.open(path, OpenOptions::new().write(true).create(true).truncate(true))?;
+        file.write_all(contents)?;
+        return Ok(());
+    }
+}
+
+// Open file handle of a Storage. Handles keep their own cursor like a std::fs::File,
+// appends always go to the end of the file.
+pub trait FileHandle: Send + Sync {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, buffer: &[u8]) -> io::Result<usize>;
+    fn seek(&self, position: SeekFrom) -> io::Result<u64>;
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, length: u64) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+// Storage used by the configs module, the data directory on disk unless another
+// storage was set before the first use
+pub fn storage() -> &'static dyn Storage {
+    return STORAGE.get_or_init(|| Box::new(FileStorage::new(data_dir()))).as_ref();
+}
+
+pub fn set_storage(storage: Box<dyn Storage>) -> Result<()> {
+    return STORAGE.set(storage).map_err(|_| anyhow!("Storage is already in use."));
+}
+
+// Tests share one memory storage & take turns using it
+#[cfg(test)]
+pub fn test_storage() -> std::sync::MutexGuard<'static, ()> {
+    static TURN: Mutex<()> = Mutex::new(());
+    let _ = set_storage(Box::new(MemoryStorage::new()));
+    return TURN.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+// Releases the lock when dropped
+pub struct StorageLock {
+    _file: Option<File>,
+    memory: Option<(Arc<MemoryLocks>, String, bool)>,
+}
+
+impl StorageLock {
+    pub fn none() -> Self {
+        return StorageLock { _file: None, memory: None };
+    }
+}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        if let Some((locks, path, exclusive)) = self.memory.take() {
+            locks.release(&path, exclusive);
+        }
+    }
+}
+
+// Registries & settings are rewritten in place, readers hold a shared lock so they
+// never see a half-updated registry. Must not be taken while already holding it.
+pub fn lock_registries(exclusive: bool) -> Result<StorageLock> {
+    if !storage().exists("configs") {
+        storage().create_dir_all("configs")?;
+    }
+    return storage().lock("configs/.lock", exclusive);
+}
+
+// Appends, compaction & log file deletion take the topic's lock exclusively, reads
+// take it shared. Taken before the registry lock, never after.
+pub fn lock_topic(topic: &str, exclusive: bool) -> Result<StorageLock> {
+    return storage().lock(&format!("logs/{}/.lock", topic), exclusive);
+}
+
+// Same options & meaning as std::fs::OpenOptions, opened through the storage
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        return OpenOptions::default();
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        return self;
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        return self;
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        return self;
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        return self;
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        return self;
+    }
+
+    pub fn open<P: AsRef<str>>(&self, path: P) -> Result<StorageFile> {
+        return storage().open(path.as_ref(), self);
+    }
+}
+
+pub struct StorageFile {
+    handle: Box<dyn FileHandle>,
+}
+
+impl StorageFile {
+    pub fn new(handle: Box<dyn FileHandle>) -> Self {
+        return StorageFile { handle };
+    }
+
+    pub fn len(&self) -> Result<u64> {
+        return Ok(self.handle.len()?);
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        return Ok(self.handle.len()? == 0);
+    }
+
+    pub fn set_len(&self, length: u64) -> Result<()> {
+        return Ok(self.handle.set_len(length)?);
+    }
+
+    pub fn sync_data(&self) -> Result<()> {
+        return Ok(self.handle.sync_data()?);
+    }
+}
+
+impl Read for &StorageFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        return self.handle.read(buffer);
+    }
+}
+
+impl Write for &StorageFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        return self.handle.write(buffer);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Seek for &StorageFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        return self.handle.seek(position);
+    }
+}
+
+impl Read for StorageFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        return self.handle.read(buffer);
+    }
+}
+
+impl Write for StorageFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        return self.handle.write(buffer);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Seek for StorageFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        return self.handle.seek(position);
+    }
+}
+
+// The data directory on disk
+pub struct FileStorage {
+    root: String,
+}
+
+impl FileStorage {
+    pub fn new(root: &str) -> Self {
+        return FileStorage { root: root.to_owned() };
+    }
+
+    fn full_path(&self, path: &str) -> String {
+        return format!("{}/{}", self.root, path);
+    }
+}
+
+impl Storage for FileStorage {
+    fn open(&self, path: &str, options: &OpenOptions) -> Result<StorageFile> {
+        let file = fs::OpenOptions::new()
+                    .read(options.read)
+                    .write(options.write)
+                    .append(options.append)
+                    .create(options.create)
+                    .truncate(options.truncate)
+                    .open(self.full_path(path))?;
+        return Ok(StorageFile::new(Box::new(DiskFile { file })));
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        return Path::new(&self.full_path(path)).exists();
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<()> {
+        fs::create_dir_all(self.full_path(path))?;
+        return Ok(());
+    }
+
+    fn remove_file(&self, path: &str) -> Result<()> {
+        fs::remove_file(self.full_path(path))?;
+        return Ok(());
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<()> {
+        fs::remove_dir_all(self.full_path(path))?;
+        return Ok(());
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        fs::rename(self.full_path(from), self.full_path(to))?;
+        return Ok(());
+    }
+
+    // Locks work between processes and between handles within the server
+    fn lock(&self, path: &str, exclusive: bool) -> Result<StorageLock> {
+        let file = match fs::OpenOptions::new().write(true).create(true).truncate(false).open(self.full_path(path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StorageLock::none()),
+            Err(e) => return Err(e.into()),
+        };
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        return Ok(StorageLock { _file: Some(file), memory: None });
+    }
+}
+
+struct DiskFile {
+    file: File,
+}
+
+impl FileHandle for DiskFile {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        return (&self.file).read(buffer);
+    }
+
+    fn write(&self, buffer: &[u8]) -> io::Result<usize> {
+        return (&self.file).write(buffer);
+    }
+
+    fn seek(&self, position: SeekFrom) -> io::Result<u64> {
+        return (&self.file).seek(position);
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        return Ok(self.file.metadata()?.len());
+    }
+
+    fn set_len(&self, length: u64) -> io::Result<()> {
+        return self.file.set_len(length);
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        return self.file.sync_data();
+    }
+}
+
+// Keeps everything in memory, for tests & throwaway deployments. Nothing survives
+// the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<HashMap<String, Arc<RwLock<Vec<u8>>>>>,
+    dirs: RwLock<HashSet<String>>,
+    locks: Arc<MemoryLocks>,
+}
+
+// Locks of the memory storage, with the same meaning as the file locks of the
+// data directory: any number of shared holders or a single exclusive one per path
+#[derive(Default)]
+struct MemoryLocks {
+    holders: Mutex<HashMap<String, LockHolders>>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockHolders {
+    shared: usize,
+    exclusive: bool,
+}
+
+impl MemoryLocks {
+    fn acquire(&self, path: &str, exclusive: bool) {
+        let mut holders = self.holders.lock().unwrap();
+        loop {
+            let path_holders = holders.entry(path.to_owned()).or_default();
+            let free = !path_holders.exclusive && (!exclusive || path_holders.shared == 0);
+            if free {
+                if exclusive {
+                    path_holders.exclusive = true;
+                } else {
+                    path_holders.shared += 1;
+                }
+                return;
+            }
+            holders = self.released.wait(holders).unwrap();
+        }
+    }
+
+    fn release(&self, path: &str, exclusive: bool) {
+        let mut holders = self.holders.lock().unwrap();
+        if let Some(path_holders) = holders.get_mut(path) {
+            if exclusive {
+                path_holders.exclusive = false;
+            } else {
+                path_holders.shared -= 1;
+            }
+            if !path_holders.exclusive && path_holders.shared == 0 {
+                holders.remove(path);
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        return MemoryStorage::default();
+    }
+
+    fn parent_exists(&self, path: &str) -> bool {
+        match path.rsplit_once('/') {
+            Some((parent, _)) => return self.dirs.read().unwrap().contains(parent),
+            None => return true,
+        }
+    }
+}
+
+fn not_found(path: &str) -> anyhow::Error {
+    return io::Error::new(io::ErrorKind::NotFound, format!("No such file or directory: {}", path)).into();
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &str, options: &OpenOptions) -> Result<StorageFile> {
+        let mut files = self.files.write().unwrap();
+        let data = match files.get(path) {
+            Some(data) => {
+                if options.truncate {
+                    data.write().unwrap().clear();
+                }
+                data.clone()
+            }
+            None => {
+                if !options.create || !self.parent_exists(path) {
+                    return Err(not_found(path));
+                }
+                let data = Arc::new(RwLock::new(Vec::new()));
+                files.insert(path.to_owned(), data.clone());
+                data
+            }
+        };
+        return Ok(StorageFile::new(Box::new(MemoryFile {
+            data,
+            cursor: Mutex::new(0),
+            append: options.append,
+        })));
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        return self.files.read().unwrap().contains_key(path) || self.dirs.read().unwrap().contains(path);
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<()> {
+        let mut dirs = self.dirs.write().unwrap();
+        let mut dir = String::new();
+        for part in path.split('/') {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(part);
+            dirs.insert(dir.clone());
+        }
+        return Ok(());
+    }
+
+    fn remove_file(&self, path: &str) -> Result<()> {
+        match self.files.write().unwrap().remove(path) {
+            Some(_) => return Ok(()),
+            None => return Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<()> {
+        if !self.dirs.read().unwrap().contains(path) {
+            return Err(not_found(path));
+        }
+        let prefix = format!("{}/", path);
+        self.files.write().unwrap().retain(|file, _| !file.starts_with(&prefix));
+        self.dirs.write().unwrap().retain(|dir| dir != path && !dir.starts_with(&prefix));
+        return Ok(());
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.write().unwrap();
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), data);
+        return Ok(());
+    }
+
+    // Nothing else can open the storage, so the locks only need to work within the process
+    fn lock(&self, path: &str, exclusive: bool) -> Result<StorageLock> {
+        if !self.parent_exists(path) {
+            return Ok(StorageLock::none());
+        }
+        self.locks.acquire(path, exclusive);
+        return Ok(StorageLock {
+            _file: None,
+            memory: Some((self.locks.clone(), path.to_owned(), exclusive)),
+        });
+    }
+}
+
+struct MemoryFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    cursor: Mutex<u64>,
+    append: bool,
+}
+
+impl FileHandle for MemoryFile {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let mut cursor = self.cursor.lock().unwrap();
+        let start = (*cursor as usize).min(data.len());
+        let length = buffer.len().min(data.len() - start);
+        buffer[..length].copy_from_slice(&data[start..start + length]);
+        *cursor += length as u64;
+        return Ok(length);
+    }
+
+    fn write(&self, buffer: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let mut cursor = self.cursor.lock().unwrap();
+        if self.append {
+            *cursor = data.len() as u64;
+        }
+        let start = *cursor as usize;
+        if data.len() < start + buffer.len() {
+            data.resize(start + buffer.len(), 0);
+        }
+        data[start..start + buffer.len()].copy_from_slice(buffer);
+        *cursor += buffer.len() as u64;
+        return Ok(buffer.len());
+    }
+
+    fn seek(&self, position: SeekFrom) -> io::Result<u64> {
+        let length = self.data.read().unwrap().len() as i64;
+        let mut cursor = self.cursor.lock().unwrap();
+        let target = match position {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => length + offset,
+            SeekFrom::Current(offset) => *cursor as i64 + offset,
+        };
+        if target < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the file."));
+        }
+        *cursor = target as u64;
+        return Ok(*cursor);
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        return Ok(self.data.read().unwrap().len() as u64);
+    }
+
+    fn set_len(&self, length: u64) -> io::Result<()> {
+        self.data.write().unwrap().resize(length as usize, 0);
+        return Ok(());
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, thread, time::Duration};
+
+    #[test]
+    fn memory_locks_exclude_like_file_locks() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.create_dir_all("logs/locked").unwrap();
+
+        // Shared locks only wait for exclusive ones
+        let shared = storage.lock("logs/locked/.lock", false).unwrap();
+        let other_shared = storage.lock("logs/locked/.lock", false).unwrap();
+        let other_path = storage.lock("logs/.lock", true).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let other = storage.clone();
+        let waiter = thread::spawn(move || {
+            let _lock = other.lock("logs/locked/.lock", true).unwrap();
+            sender.send(()).unwrap();
+        });
+        drop(shared);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(other_shared);
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        waiter.join().unwrap();
+        drop(other_path);
+
+        // Paths in missing directories aren't locked
+        let _missing = storage.lock("logs/missing/.lock", true).unwrap();
+        let _missing_again = storage.lock("logs/missing/.lock", true).unwrap();
+    }
+}
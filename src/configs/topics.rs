@@ -1,14 +1,15 @@
 #![allow(unused)]
 
-use std::{path::Path, fs::{self, File, OpenOptions}, io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read}, sync::Mutex};
+use std::io::{BufWriter, Write, Seek, SeekFrom, BufReader, Read};
+use super::storage::{storage, lock_registries, lock_topic, OpenOptions, StorageFile};
 use anyhow::{Result, anyhow};
-use crate::subjects::{topic::Topic, keys::generate_key, consumer::Consumer, event::Event};
+use crate::subjects::{topic::Topic, keys::generate_key, consumer::Consumer, event::{Event, format_event_id, now}, settings::{Settings, Compression, DEFAULT_SEGMENT_BYTES}};
+use super::{errors::{CorruptRecord, UnknownTopic, TopicExists}, indexes::{index_record, time_index_record, delete_index, read_size_index, write_size_index, delete_compaction_map, CompactionMap, get_base_offset, rebuild_index_from, ensure_index}};
 
 pub fn create_topic_dir(topic: &str) -> Result<()> {
-    let path = format!("sailfish/logs/{}", topic);
-    let path = Path::new(&path);
-    if !path.exists() {
-        fs::create_dir_all(path)?;
+    let path = format!("logs/{}", topic);
+    if !storage().exists(&path) {
+        storage().create_dir_all(&path)?;
     }
     create_topic_file(topic, 0)?;
     create_topic_file(topic, 1)?;
@@ -16,77 +17,211 @@ pub fn create_topic_dir(topic: &str) -> Result<()> {
 }
 
 pub fn delete_topic_dir(topic: &str) -> Result<()> {
-    let path = format!("sailfish/logs/{}", topic);
-    let path = Path::new(&path);
-    if path.exists() {
-        fs::remove_dir_all(path)?;
+    let path = format!("logs/{}", topic);
+    if storage().exists(&path) {
+        storage().remove_dir_all(&path)?;
     }
     return Ok(());
 }
 
-fn create_topic_file(topic: &str, file: usize) -> Result<File> {
-    let path = format!("sailfish/logs/{}/{}", topic, file);
-    let path = Path::new(&path);
-    let file = OpenOptions::new()
+// Segment header: magic (4 bytes) + format version (4 bytes). Log files written
+// before the header existed start straight with records and are read as version 1,
+// the legacy layout without checksums or metadata. Version 2 adds both, version 3
+// adds per-record compression.
+const SEGMENT_MAGIC:&[u8; 4] = b"SFLG";
+const SEGMENT_VERSION:u32 = 3;
+const SEGMENT_HEADER_LENGTH:u64 = 8;
+
+pub struct SegmentHeader {
+    pub version: u32,
+    // Offset of the first record
+    pub length: u64,
+}
+
+impl SegmentHeader {
+    // Bytes in front of every record of the log file
+    pub fn record_header_length(&self) -> u64 {
+        if self.version == 1 {
+            return LEGACY_RECORD_HEADER_LENGTH;
+        }
+        return RECORD_HEADER_LENGTH;
+    }
+}
+
+fn write_segment_header(file: &mut StorageFile) -> Result<()> {
+    if file.len()? == 0 {
+        file.write_all(SEGMENT_MAGIC)?;
+        file.write_all(&SEGMENT_VERSION.to_be_bytes())?;
+    }
+    return Ok(());
+}
+
+pub fn read_segment_header(file: &StorageFile) -> Result<SegmentHeader> {
+    let mut header = SegmentHeader {
+        version: 1,
+        length: 0,
+    };
+    if file.len()? < SEGMENT_HEADER_LENGTH {
+        return Ok(header);
+    }
+
+    let mut reader = BufReader::with_capacity(SEGMENT_HEADER_LENGTH as usize, file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic_buffer = [0u8; 4];
+    reader.read_exact(&mut magic_buffer)?;
+    if &magic_buffer != SEGMENT_MAGIC {
+        return Ok(header);
+    }
+
+    let mut version_buffer = [0u8; 4];
+    reader.read_exact(&mut version_buffer)?;
+    header.version = u32::from_be_bytes(version_buffer);
+    header.length = SEGMENT_HEADER_LENGTH;
+
+    if header.version > SEGMENT_VERSION {
+        return Err(anyhow!("Unsupported log file format version {}.", header.version));
+    }
+    return Ok(header);
+}
+
+fn create_topic_file(topic: &str, file: usize) -> Result<StorageFile> {
+    let path = format!("logs/{}/{}", topic, file);
+    let mut file = OpenOptions::new()
                     .write(true)
                     .create(true)
+                    .truncate(false)
                     .open(path)?;
+    write_segment_header(&mut file)?;
     return Ok(file);
 }
 
-fn get_latest_topic_file(topic: &str) -> Result<File> {
+// A log file rolls over once it reaches the topic's segment size, or once its
+// first event is older than the topic's segment age. Log files without events
+// never roll, so tiny limits can't bump the topic in a loop.
+fn should_roll(file: &StorageFile, settings: &Settings) -> Result<bool> {
+    let header = read_segment_header(file)?;
+    let len = file.len()?;
+    if len <= header.length {
+        return Ok(false);
+    }
+
+    // Log files without a header keep the legacy layout, new records go to a new one
+    if header.length == 0 {
+        return Ok(true);
+    }
+
+    if len >= settings.segment_bytes.unwrap_or(DEFAULT_SEGMENT_BYTES) {
+        return Ok(true);
+    }
+
+    if let Some(segment_ms) = settings.segment_ms {
+        let mut reader = BufReader::new(file);
+        let first_timestamp = read_record_timestamp(&mut reader, &header, header.length)?;
+        if now().saturating_sub(first_timestamp) >= segment_ms {
+            return Ok(true);
+        }
+    }
+
+    return Ok(false);
+}
+
+fn get_latest_topic_file(topic: &str, settings: &Settings) -> Result<(StorageFile, u64)> {
     let mut topic = Topic::hydrate(topic)?;
-    let path = format!("sailfish/logs/{}/{}", topic.name, topic.curr_log_file);
-    let path = Path::new(&path);
-    let file = OpenOptions::new()
+    let path = format!("logs/{}/{}", topic.name, topic.curr_log_file);
+    let mut file = OpenOptions::new()
                     .read(true)
                     .append(true)
                     .open(path)?;
 
+    // Log files created by older builds (or truncated by recovery) may still be empty
+    write_segment_header(&mut file)?;
 
-    // Greater than or equal to 1GB
-    if file.metadata()?.len() >= 1000000000 {
+    if should_roll(&file, settings)? {
         topic.bump()?;
 
         // Always have curr + next file
         let _ = create_topic_file(&topic.name, (topic.curr_log_file + 1) as usize)?;
 
-        let file = get_latest_topic_file(&topic.name)?;
-        return Ok(file);
+        return get_latest_topic_file(&topic.name, settings);
     }
 
-    return Ok(file);
+    return Ok((file, topic.curr_log_file));
 }
 
-fn get_topic_file(topic: &str, file_id: &u64) -> Result<File> {
-    let path = format!("sailfish/logs/{}/{}", topic, file_id);
-    let path = Path::new(&path);
+pub fn get_topic_file(topic: &str, file_id: &u64) -> Result<StorageFile> {
+    let path = format!("logs/{}/{}", topic, file_id);
     let file = OpenOptions::new()
                     .read(true)
                     .open(path)?;
     return Ok(file);
 }
 
+// Log files without a header were written before event IDs had a topic tag
+pub fn is_legacy_log_file(topic: &str, log_file: &u64) -> Result<bool> {
+    if !storage().exists(&format!("logs/{}/{}", topic, log_file)) {
+        return Ok(false);
+    }
+    let file = get_topic_file(topic, log_file)?;
+    return Ok(read_segment_header(&file)?.version == 1);
+}
+
 pub fn topic_exists(topic: &str) -> bool {
-    let path = format!("sailfish/logs/{}", topic);
-    let path = Path::new(&path);
-    return path.exists();
+    let path = format!("logs/{}", topic);
+    return storage().exists(&path);
 }
 
 fn create_configs_dir() -> Result<()> {
-    let path = Path::new("sailfish/configs");
-    if !path.exists() {
-        fs::create_dir_all(path)?;
+    let path = "configs";
+    if !storage().exists(path) {
+        storage().create_dir_all(path)?;
     }
     return Ok(());
 }
 
+pub fn get_topic_settings(topic: &str) -> Result<Settings> {
+    let _lock = lock_registries(false)?;
+    let path = format!("configs/settings/{}", topic);
+    if !storage().exists(&path) {
+        return Ok(Settings::default());
+    }
+    let contents = storage().read_to_string(&path)?;
+    return Settings::parse(&contents);
+}
+
+pub fn set_topic_settings(topic: &str, settings: &Settings) -> Result<()> {
+    let _lock = lock_registries(true)?;
+    let path = "configs/settings";
+    if !storage().exists(path) {
+        storage().create_dir_all(path)?;
+    }
+    let path = format!("configs/settings/{}", topic);
+    storage().write(&path, settings.serialize().as_bytes())?;
+    return Ok(());
+}
+
+pub fn delete_topic_settings(topic: &str) -> Result<()> {
+    let _lock = lock_registries(true)?;
+    let path = format!("configs/settings/{}", topic);
+    if storage().exists(&path) {
+        storage().remove_file(&path)?;
+    }
+    return Ok(());
+}
+
+// Checks & registers the topic under one lock so two creators can't both add it
 pub fn add_topic_to_config(topic: &Topic) -> Result<()> {
+    let _lock = lock_registries(true)?;
     create_configs_dir()?;
-    let path = Path::new("sailfish/configs/topics");
+    let path = "configs/topics";
     let file = OpenOptions::new()
+                .read(true)
                 .append(true)
+                .create(true)
                 .open(path)?;
+    if topic_exists(&topic.name) || find_topic_record(&file, &topic.name).is_ok() {
+        return Err(TopicExists { topic: topic.name.clone() }.into());
+    }
+    create_topic_dir(&topic.name)?;
 
     let mut writer = BufWriter::new(&file);
     writer.seek(SeekFrom::End(0))?;
@@ -106,40 +241,48 @@ pub fn add_topic_to_config(topic: &Topic) -> Result<()> {
     return Ok(());
 }
 
-pub fn update_topic_in_config(topic: &Topic) -> Result<()> {
+// Retention moves the first log file while writers move the current one, so
+// each only writes the field it changed
+pub fn update_first_log_file_in_config(topic: &Topic) -> Result<()> {
+    return update_log_file_in_config(topic, 0, topic.first_log_file);
+}
+
+pub fn update_curr_log_file_in_config(topic: &Topic) -> Result<()> {
+    return update_log_file_in_config(topic, 8, topic.curr_log_file);
+}
+
+fn update_log_file_in_config(topic: &Topic, field_offset: u64, log_file: u64) -> Result<()> {
+    let _lock = lock_registries(true)?;
     create_configs_dir()?;
-    let path = Path::new("sailfish/configs/topics");
+    let path = "configs/topics";
     let file = OpenOptions::new()
+                .read(true)
                 .write(true)
                 .open(path)?;
 
-    let name_length = topic.name.as_bytes().len() as u64;
-    let mut writer = BufWriter::with_capacity(16, &file);
+    let record_offset = find_topic_record(&file, &topic.name)?;
+    let name_length = topic.name.len() as u64;
+    let mut writer = BufWriter::with_capacity(8, &file);
 
-    // Skip to offset + name len & name value
-    writer.seek(SeekFrom::Start(topic.offset + 8 + name_length))?;
+    // Skip to offset + name len & name value + field
+    writer.seek(SeekFrom::Start(record_offset + 8 + name_length + field_offset))?;
 
-    // Write file info (8 bytes ea)
-    writer.write_all(&topic.first_log_file.to_be_bytes())?;
-    writer.write_all(&topic.curr_log_file.to_be_bytes())?;
+    writer.write_all(&log_file.to_be_bytes())?;
 
     writer.flush()?;
 
     return Ok(());
 }
 
-pub fn get_topic_from_config(topic: &mut Topic) -> Result<()> {
-    let path = Path::new("sailfish/configs/topics");
-    let file = OpenOptions::new()
-                .read(true)
-                .open(path)?;
-
-    let mut reader = BufReader::new(&file);
+// Offset of the topic's record in the registry. Deleting a topic moves the records
+// after it, so the offset is looked up under the registry lock before every write.
+fn find_topic_record(file: &StorageFile, name: &str) -> Result<u64> {
+    let total_bytes = file.len()?;
+    let mut reader = BufReader::new(file);
     reader.seek(SeekFrom::Start(0))?;
 
     let mut bytes_read:u64 = 0;
-
-    loop {
+    while bytes_read < total_bytes {
         // Read & parse name length from buffer (8 bytes)
         let mut name_length_buffer = [0u8; 8];
         reader.read_exact(&mut name_length_buffer)?;
@@ -148,22 +291,8 @@ pub fn get_topic_from_config(topic: &mut Topic) -> Result<()> {
         // Read & parse name from buffer (??? bytes)
         let mut name_buffer:Vec<u8> = vec![0u8; name_length as usize];
         reader.read_exact(&mut name_buffer[..])?;
-        let name = std::str::from_utf8(&name_buffer).unwrap_or("failed");
-
-        if name == &topic.name{
-            // Read & parse first log file
-            let mut first_log_buffer = [0u8; 8];
-            reader.read_exact(&mut first_log_buffer)?;
-            topic.first_log_file = u64::from_be_bytes(first_log_buffer);
-
-            // Read & parse current log file
-            let mut curr_log_buffer = [0u8; 8];
-            reader.read_exact(&mut curr_log_buffer)?;
-            topic.curr_log_file = u64::from_be_bytes(curr_log_buffer);
-
-            topic.offset = bytes_read;
-            
-            break;
+        if name_buffer == name.as_bytes() {
+            return Ok(bytes_read);
         }
 
         // Skip the next 16 bytes (2x 8 byte file info)
@@ -171,14 +300,40 @@ pub fn get_topic_from_config(topic: &mut Topic) -> Result<()> {
 
         bytes_read += 8 + name_length + 16;
     }
-    
+    return Err(UnknownTopic { topic: name.to_owned() }.into());
+}
+
+pub fn get_topic_from_config(topic: &mut Topic) -> Result<()> {
+    let _lock = lock_registries(false)?;
+    let path = "configs/topics";
+    let file = OpenOptions::new()
+                .read(true)
+                .open(path)?;
+
+    topic.offset = find_topic_record(&file, &topic.name)?;
+
+    let mut reader = BufReader::new(&file);
+    reader.seek(SeekFrom::Start(topic.offset + 8 + topic.name.len() as u64))?;
+
+    // Read & parse first log file
+    let mut first_log_buffer = [0u8; 8];
+    reader.read_exact(&mut first_log_buffer)?;
+    topic.first_log_file = u64::from_be_bytes(first_log_buffer);
+
+    // Read & parse current log file
+    let mut curr_log_buffer = [0u8; 8];
+    reader.read_exact(&mut curr_log_buffer)?;
+    topic.curr_log_file = u64::from_be_bytes(curr_log_buffer);
+
     return Ok(());
 }
 
 pub fn delete_topic(topic: &Topic) -> Result<()> {
-    let path = Path::new("sailfish/configs/topics");
+    let _lock = lock_registries(true)?;
+    let path = "configs/topics";
     let file = OpenOptions::new()
                 .read(true)
+                .write(true)
                 .open(path)?;
 
     let mut reader = BufReader::new(&file);
@@ -200,7 +355,7 @@ pub fn delete_topic(topic: &Topic) -> Result<()> {
         // Calc total bytes for this topic
         let topic_bytes:i64 = 16 + 8 + name_length as i64;
 
-        if name == &topic.name{
+        if name == topic.name{
             let mut start_buffer:Vec<u8> = vec![0; bytes_read];
 
             // Read everything from start until topic into buffer
@@ -216,8 +371,8 @@ pub fn delete_topic(topic: &Topic) -> Result<()> {
             // Write buffers
             let mut writer = BufWriter::new(&file);
             writer.seek(SeekFrom::Start(0))?;
-            writer.write_all(&mut start_buffer[..])?;
-            writer.write_all(&mut end_buffer[..])?;
+            writer.write_all(&start_buffer[..])?;
+            writer.write_all(&end_buffer[..])?;
 
             // Truncate file to correct size
             file.set_len(start_buffer.len() as u64 + end_buffer.len() as u64)?;
@@ -234,8 +389,12 @@ pub fn delete_topic(topic: &Topic) -> Result<()> {
     return Ok(());
 }
 
-pub fn list_topics() -> Result<()> {
-    let path = Path::new("sailfish/configs/topics");
+pub fn get_topics() -> Result<Vec<Topic>> {
+    let _lock = lock_registries(false)?;
+    let path = "configs/topics";
+    if !storage().exists(path) {
+        return Ok(Vec::new());
+    }
     let file = OpenOptions::new()
                 .read(true)
                 .open(path)?;
@@ -243,8 +402,9 @@ pub fn list_topics() -> Result<()> {
     let mut reader = BufReader::new(&file);
     reader.seek(SeekFrom::Start(0))?;
 
-    let total_bytes = file.metadata()?.len();
+    let total_bytes = file.len()?;
     let mut bytes_read = 0;
+    let mut topics:Vec<Topic> = Vec::new();
 
     loop {
         if bytes_read == total_bytes {
@@ -278,89 +438,608 @@ pub fn list_topics() -> Result<()> {
             curr_log_file,
             offset: bytes_read,
         };
-        println!("{}", topic);
+        topics.push(topic);
 
         bytes_read += 8 + name_length + 16;
     }
 
-    return Ok(());
+    return Ok(topics);
 }
 
-pub fn write(topic: &str, content: &[u8]) -> Result<()> {
+// Record layout: record length (8 bytes) + CRC32 of length & record (4 bytes) + record
+const RECORD_HEADER_LENGTH:u64 = 12;
 
-    let content_length = content.len() as u64;
+// Legacy record layout (version 1): content length (8 bytes) + content
+const LEGACY_RECORD_HEADER_LENGTH:u64 = 8;
 
-    //let eid = generate_key();
+fn record_checksum(record_length_buffer: &[u8; 8], record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(record_length_buffer);
+    hasher.update(record);
+    return hasher.finalize();
+}
 
-    let capacity = 8 + content_length as usize;
+fn write_field(record: &mut Vec<u8>, field: &[u8]) {
+    record.extend_from_slice(&(field.len() as u64).to_be_bytes());
+    record.extend_from_slice(field);
+}
+
+// Event layout: timestamp (8 bytes) + key length & key + header count (8 bytes)
+// + name length & name / value length & value per header + content
+fn encode_event(event: &Event) -> Vec<u8> {
+    let mut record:Vec<u8> = Vec::with_capacity(24 + event.content.len());
+    record.extend_from_slice(&event.timestamp.to_be_bytes());
+    write_field(&mut record, event.key.as_deref().unwrap_or("").as_bytes());
+    record.extend_from_slice(&(event.headers.len() as u64).to_be_bytes());
+    for (name, value) in &event.headers {
+        write_field(&mut record, name.as_bytes());
+        write_field(&mut record, value.as_bytes());
+    }
+    record.extend_from_slice(&event.content);
+    return record;
+}
+
+fn read_u64(reader: &mut &[u8]) -> Result<u64> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    return Ok(u64::from_be_bytes(buffer));
+}
+
+fn read_field(reader: &mut &[u8]) -> Result<String> {
+    let length = read_u64(reader)?;
+    if length > reader.len() as u64 {
+        return Err(anyhow!("Field length exceeds record."));
+    }
+    let (field, rest) = reader.split_at(length as usize);
+    *reader = rest;
+    return Ok(String::from_utf8(field.to_vec())?);
+}
+
+// Legacy events carry no timestamp, key or headers, they read as written at the epoch
+fn decode_legacy_event(eid: String, record: &[u8]) -> Event {
+    return Event {
+        eid,
+        timestamp: 0,
+        key: None,
+        headers: Vec::new(),
+        content: record.to_vec(),
+    };
+}
+
+fn decode_event(eid: String, record: &[u8]) -> Result<Event> {
+    let mut reader = record;
+    let timestamp = read_u64(&mut reader)?;
+    let key = read_field(&mut reader)?;
+    let header_count = read_u64(&mut reader)?;
+    let mut headers:Vec<(String, String)> = Vec::new();
+    for _ in 0..header_count {
+        let name = read_field(&mut reader)?;
+        let value = read_field(&mut reader)?;
+        headers.push((name, value));
+    }
+    return Ok(Event {
+        eid,
+        timestamp,
+        key: if key.is_empty() { None } else { Some(key) },
+        headers,
+        content: reader.to_vec(),
+    });
+}
+
+// Compressed event layout (version 3): timestamp (8 bytes) + codec (1 byte) + raw
+// length (8 bytes) + the rest of the event layout, compressed with the codec. The
+// timestamp stays uncompressed so the time index can be built without decompressing.
+const COMPRESSED_HEADER_LENGTH:usize = 17;
+
+fn codec_id(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => return 0,
+        Compression::Zstd => return 1,
+        Compression::Lz4 => return 2,
+    }
+}
+
+fn encode_compressed_event(event: &Event, compression: Compression) -> Result<Vec<u8>> {
+    let encoded = encode_event(event);
+    let raw = &encoded[8..];
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Zstd => Some(zstd::bulk::compress(raw, 0)?),
+        Compression::Lz4 => Some(lz4_flex::block::compress(raw)),
+    };
+
+    // Events that don't shrink are stored as is
+    let (codec, payload) = match compressed {
+        Some(compressed) if compressed.len() < raw.len() => (codec_id(compression), compressed),
+        _ => (codec_id(Compression::None), raw.to_vec()),
+    };
+
+    let mut record:Vec<u8> = Vec::with_capacity(COMPRESSED_HEADER_LENGTH + payload.len());
+    record.extend_from_slice(&event.timestamp.to_be_bytes());
+    record.push(codec);
+    record.extend_from_slice(&(raw.len() as u64).to_be_bytes());
+    record.extend_from_slice(&payload);
+    return Ok(record);
+}
+
+fn decode_compressed_event(eid: String, record: &[u8]) -> Result<Event> {
+    if record.len() < COMPRESSED_HEADER_LENGTH {
+        return Err(anyhow!("Record is shorter than its header."));
+    }
+    let (timestamp, rest) = record.split_at(8);
+    let codec = rest[0];
+    let raw_length = u64::from_be_bytes(rest[1..9].try_into()?) as usize;
+    let payload = &rest[9..];
+
+    let mut decoded:Vec<u8> = Vec::with_capacity(8 + raw_length);
+    decoded.extend_from_slice(timestamp);
+    match codec {
+        0 => decoded.extend_from_slice(payload),
+        1 => decoded.extend_from_slice(&zstd::bulk::decompress(payload, raw_length)?),
+        2 => decoded.extend_from_slice(&lz4_flex::block::decompress(payload, raw_length)?),
+        _ => return Err(anyhow!("Unknown compression codec {}.", codec)),
+    }
+    return decode_event(eid, &decoded);
+}
+
+fn encode_record(version: u32, event: &Event, compression: Compression) -> Result<Vec<u8>> {
+    match version {
+        2 => return Ok(encode_event(event)),
+        3 => return encode_compressed_event(event, compression),
+        _ => return Err(anyhow!("Unsupported log file format version {}.", version)),
+    }
+}
+
+fn decode_record(version: u32, eid: String, record: &[u8]) -> Result<Event> {
+    match version {
+        1 => return Ok(decode_legacy_event(eid, record)),
+        2 => return decode_event(eid, record),
+        3 => return decode_compressed_event(eid, record),
+        _ => return Err(anyhow!("Unsupported log file format version {}.", version)),
+    }
+}
+
+// Returns the log file the record was appended to
+pub fn write(topic: &str, event: &Event, sync: bool) -> Result<u64> {
+    let _lock = lock_topic(topic, true)?;
+
+    let settings = get_topic_settings(topic)?;
+    let (file, log_file) = get_latest_topic_file(topic, &settings)?;
+
+    // Log files keep the format they were created with
+    let header = read_segment_header(&file)?;
+    let record = encode_record(header.version, event, settings.compression.unwrap_or(Compression::None))?;
+    let record_length = record.len() as u64;
+    let record_length_buffer = record_length.to_be_bytes();
+    let checksum = record_checksum(&record_length_buffer, &record);
+
+    let capacity = RECORD_HEADER_LENGTH as usize + record.len();
 
-    let file = get_latest_topic_file(topic)?;
     let mut writer = BufWriter::with_capacity(capacity, file);
-    writer.seek(SeekFrom::End(0))?;
+    let position = writer.seek(SeekFrom::End(0))?;
 
-    // 36 bytes
-    //writer.write_all(&eid.as_bytes())?;
-    writer.write_all(&content_length.to_be_bytes())?;
-    writer.write_all(&content)?;
+    writer.write_all(&record_length_buffer)?;
+    writer.write_all(&checksum.to_be_bytes())?;
+    writer.write_all(&record)?;
 
     writer.flush()?;
 
+    if sync {
+        writer.get_ref().sync_data()?;
+    }
+
+    index_record(topic, &log_file, position)?;
+    time_index_record(topic, &log_file, position, event.timestamp)?;
+
+    return Ok(log_file);
+}
+
+pub fn sync_topic_file(topic: &str, log_file: &u64) -> Result<()> {
+    let file = get_topic_file(topic, log_file)?;
+    file.sync_data()?;
     return Ok(());
 }
 
 pub fn read(consumer: &mut Consumer) -> Result<Event> {
 
+    // Retention may have deleted the log file the consumer was on
+    if !storage().exists(&format!("logs/{}/{}", consumer.topic, consumer.log_file)) {
+        let topic = Topic::hydrate(&consumer.topic)?;
+        if consumer.log_file < topic.first_log_file {
+            consumer.log_file = topic.first_log_file;
+            consumer.log_offset = 0;
+        }
+    }
+
     let mut file = get_topic_file(&consumer.topic, &consumer.log_file)?;
+    let mut file_length = file.len()?;
+    let mut header = read_segment_header(&file)?;
+    consumer.log_offset = consumer.log_offset.max(header.length);
 
-    if consumer.log_offset == file.metadata()?.len() {
+    if consumer.log_offset == file_length {
         let topic = Topic::hydrate(&consumer.topic)?;
-        if topic.curr_log_file != consumer.log_file {
-            consumer.log_offset = 0;
+
+        // Compaction & tiny segment sizes can leave log files without events
+        while consumer.log_offset == file_length && topic.curr_log_file != consumer.log_file {
             consumer.log_file += 1;
             file = get_topic_file(&consumer.topic, &consumer.log_file)?;
-        } else {
+            file_length = file.len()?;
+            header = read_segment_header(&file)?;
+            consumer.log_offset = header.length;
+        }
+        if consumer.log_offset == file_length {
             return Err(anyhow!("EOF"));
         }
     }
 
-    let mut reader = BufReader::new(&file);
-    reader.seek(SeekFrom::Start(consumer.log_offset))?;
+    let map = CompactionMap::open(&consumer.topic, &consumer.log_file)?;
+    let (event, next_offset) = read_record(&file, &header, map.as_ref(), &consumer.topic, consumer.log_file, consumer.log_offset)?;
+    consumer.log_offset = next_offset;
 
-    //let mut eid_buffer:Vec<u8> = vec![0u8; 36];
-    //reader.read_exact(&mut eid_buffer)?;
-    //let eid = String::from_utf8(eid_buffer)?;
+    return Ok(event);
+}
 
-    let mut content_length_buffer = [0u8; 8];
-    reader.read_exact(&mut content_length_buffer)?;
-    let content_length = u64::from_be_bytes(content_length_buffer);
+// Reads a single event without moving any consumer
+pub fn read_at(topic: &str, log_file: &u64, position: u64) -> Result<Event> {
+    let file = get_topic_file(topic, log_file)?;
+    let header = read_segment_header(&file)?;
+    let position = position.max(header.length);
+    if position == file.len()? {
+        return Err(anyhow!("EOF"));
+    }
+    let map = CompactionMap::open(topic, log_file)?;
+    let (event, _) = read_record(&file, &header, map.as_ref(), topic, *log_file, position)?;
+    return Ok(event);
+}
 
-    let mut content_buffer:Vec<u8> = vec![0u8; content_length as usize];
-    reader.read_exact(&mut content_buffer)?;
+// Returns the event and the position of the record after it. The compaction map of
+// the log file, if it has one, gives the event ID its original position.
+pub fn read_record(file: &StorageFile, header: &SegmentHeader, map: Option<&CompactionMap>, topic: &str, log_file: u64, record_offset: u64) -> Result<(Event, u64)> {
+    let file_length = file.len()?;
+    let record_header_length = header.record_header_length();
+    let corrupt = |skip_eid: Option<String>| CorruptRecord {
+        log_file,
+        log_offset: record_offset,
+        skip_eid,
+    };
 
-    consumer.log_offset += 8 + content_length;
+    // Never trust a length prefix that points past the end of the segment
+    if record_offset + record_header_length > file_length {
+        return Err(corrupt(None).into());
+    }
 
-    let event = Event {
-        eid: format!("{}-{}", consumer.log_offset, consumer.log_file),
-        content: content_buffer,
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(record_offset))?;
+
+    let mut record_length_buffer = [0u8; 8];
+    reader.read_exact(&mut record_length_buffer)?;
+    let record_length = u64::from_be_bytes(record_length_buffer);
+
+    if record_length > file_length - record_offset - record_header_length {
+        return Err(corrupt(None).into());
+    }
+
+    // Legacy records have no checksum
+    let mut checksum:Option<u32> = None;
+    if header.version > 1 {
+        let mut checksum_buffer = [0u8; 4];
+        reader.read_exact(&mut checksum_buffer)?;
+        checksum = Some(u32::from_be_bytes(checksum_buffer));
+    }
+
+    let mut record_buffer:Vec<u8> = vec![0u8; record_length as usize];
+    reader.read_exact(&mut record_buffer)?;
+
+    let next_offset = record_offset + record_header_length + record_length;
+    // Records are copied as is by compaction, so they end as far from their original
+    // start as they always did
+    let original_offset = match map {
+        Some(map) => map.original_position(record_offset)? + (next_offset - record_offset),
+        None => next_offset,
     };
-    
-    return Ok(event);
+    let eid = format_event_id(topic, original_offset, log_file);
+
+    if checksum.is_some_and(|checksum| checksum != record_checksum(&record_length_buffer, &record_buffer)) {
+        return Err(corrupt(Some(eid)).into());
+    }
+
+    let event = decode_record(header.version, eid.clone(), &record_buffer).map_err(|_| corrupt(Some(eid)))?;
+
+    return Ok((event, next_offset));
 }
 
-pub fn delete_old_logs(curr_file: &u64, topic: &str) -> Result<()> {
-    let mut current_file:u64 = 0;
-    loop {
-        if &current_file == curr_file {
-            break;
+pub fn read_record_timestamp(reader: &mut BufReader<&StorageFile>, header: &SegmentHeader, position: u64) -> Result<u64> {
+    match header.version {
+        1 => return Ok(0),
+        // The timestamp leads the record in every format since
+        2..=3 => {
+            reader.seek(SeekFrom::Start(position + RECORD_HEADER_LENGTH))?;
+            let mut timestamp_buffer = [0u8; 8];
+            reader.read_exact(&mut timestamp_buffer)?;
+            return Ok(u64::from_be_bytes(timestamp_buffer));
         }
-        let path = format!("sailfish/logs/{}/{}", topic, current_file);
-        let path = Path::new(&path);
-        if path.exists() {
-            fs::remove_file(path)?;
-        } else {
+        _ => return Err(anyhow!("Unsupported log file format version {}.", header.version)),
+    }
+}
+
+// Size of the log file with every event decompressed & its size on disk
+pub fn get_log_file_sizes(topic: &str, log_file: &u64) -> Result<(u64, u64)> {
+    let file = get_topic_file(topic, log_file)?;
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+    if header.version < 3 {
+        return Ok((file_length, file_length));
+    }
+
+    // Carry on from the records the size index already added up
+    let (mut position, mut raw_records_length) = match read_size_index(topic, log_file)? {
+        Some((position, raw_records_length)) if position <= file_length => (position, raw_records_length),
+        _ => (header.length, 0),
+    };
+    let indexed_position = position;
+    let mut reader = BufReader::new(&file);
+    while position < file_length {
+        let next_position = next_record_position(&mut reader, &header, position)?;
+
+        // Skip to the raw length after the timestamp & codec
+        reader.seek(SeekFrom::Start(position + RECORD_HEADER_LENGTH + 9))?;
+        let mut raw_length_buffer = [0u8; 8];
+        reader.read_exact(&mut raw_length_buffer)?;
+        raw_records_length += RECORD_HEADER_LENGTH + COMPRESSED_HEADER_LENGTH as u64 + u64::from_be_bytes(raw_length_buffer);
+
+        position = next_position;
+    }
+    if position != indexed_position {
+        write_size_index(topic, log_file, position, raw_records_length)?;
+    }
+    return Ok((header.length + raw_records_length, file_length));
+}
+
+pub fn next_record_position(reader: &mut BufReader<&StorageFile>, header: &SegmentHeader, position: u64) -> Result<u64> {
+    reader.seek(SeekFrom::Start(position))?;
+    let mut record_length_buffer = [0u8; 8];
+    reader.read_exact(&mut record_length_buffer)?;
+    let record_length = u64::from_be_bytes(record_length_buffer);
+    return Ok(position + header.record_header_length() + record_length);
+}
+
+fn verify_record(reader: &mut BufReader<&StorageFile>, header: &SegmentHeader, offset: u64) -> Result<bool> {
+    // Legacy records can only be checked against their length
+    if header.version == 1 {
+        return Ok(true);
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut record_length_buffer = [0u8; 8];
+    reader.read_exact(&mut record_length_buffer)?;
+    let record_length = u64::from_be_bytes(record_length_buffer);
+
+    let mut checksum_buffer = [0u8; 4];
+    reader.read_exact(&mut checksum_buffer)?;
+    let checksum = u32::from_be_bytes(checksum_buffer);
+
+    let mut record_buffer:Vec<u8> = vec![0u8; record_length as usize];
+    reader.read_exact(&mut record_buffer)?;
+
+    return Ok(checksum == record_checksum(&record_length_buffer, &record_buffer));
+}
+
+// Truncates a torn record left at the tail of the current log file by an interrupted write.
+// Returns the number of bytes that were dropped.
+pub fn recover_topic_file(topic: &Topic) -> Result<u64> {
+    let path = format!("logs/{}/{}", topic.name, topic.curr_log_file);
+    let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+
+    let file_length = file.len()?;
+    let header = read_segment_header(&file)?;
+    let record_header_length = header.record_header_length();
+    let mut reader = BufReader::new(&file);
+
+    // Walk the length prefixes until a record runs past the end of the file
+    let mut offset:u64 = header.length;
+    let mut last_record:Option<u64> = None;
+    while offset + record_header_length <= file_length {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut record_length_buffer = [0u8; 8];
+        reader.read_exact(&mut record_length_buffer)?;
+        let record_length = u64::from_be_bytes(record_length_buffer);
+
+        if record_length > file_length - offset - record_header_length {
             break;
         }
-        current_file += 1;
+
+        last_record = Some(offset);
+        offset += record_header_length + record_length;
+    }
+
+    // The last complete record may still hold a partially flushed payload
+    if let Some(last_record) = last_record {
+        if !verify_record(&mut reader, &header, last_record)? {
+            offset = last_record;
+        }
+    }
+
+    if offset < file_length {
+        let base_offset = get_base_offset(&topic.name, &topic.curr_log_file)?;
+        file.set_len(offset)?;
+        // The index may point at dropped records, rebuild it from the same base offset
+        delete_index(&topic.name, &topic.curr_log_file)?;
+        rebuild_index_from(&topic.name, &topic.curr_log_file, base_offset)?;
+    }
+
+    return Ok(file_length - offset);
+}
+
+// Deletes every log file before the first retained one. Log files go oldest first,
+// so the ones an earlier run left behind always lead straight up to it.
+pub fn delete_old_logs(first_file: &u64, topic: &str) -> Result<()> {
+    // The base offset of the first retained log file can't be counted once the log
+    // files before it are gone, it has to be in its index
+    ensure_index(topic, first_file)?;
+
+    let mut oldest_file = *first_file;
+    while oldest_file > 0 && storage().exists(&format!("logs/{}/{}", topic, oldest_file - 1)) {
+        oldest_file -= 1;
+    }
+    for log_file in oldest_file..*first_file {
+        storage().remove_file(&format!("logs/{}/{}", topic, log_file))?;
+        delete_index(topic, &log_file)?;
+        delete_compaction_map(topic, &log_file)?;
     }
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::test_storage;
+
+    fn test_event(content: &str) -> Event {
+        return Event::new(content.as_bytes().to_vec(), Some("key".to_string()), vec![("name".to_string(), "value".to_string())]);
+    }
+
+    // Record with the length & CRC header of versions 2 and up
+    fn framed_record(record: &[u8]) -> Vec<u8> {
+        let length = (record.len() as u64).to_be_bytes();
+        let mut framed = length.to_vec();
+        framed.extend_from_slice(&record_checksum(&length, record).to_be_bytes());
+        framed.extend_from_slice(record);
+        return framed;
+    }
+
+    fn write_log_file(topic: &str, contents: &[u8]) {
+        storage().create_dir_all(&format!("logs/{}", topic)).unwrap();
+        storage().write(&format!("logs/{}/0", topic), contents).unwrap();
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let event = test_event(&"compressible ".repeat(20));
+        let formats = [(2, Compression::None), (3, Compression::None), (3, Compression::Zstd), (3, Compression::Lz4)];
+        for (version, compression) in formats {
+            let record = encode_record(version, &event, compression).unwrap();
+            let decoded = decode_record(version, "eid".to_string(), &record).unwrap();
+            assert_eq!(decoded.eid, "eid");
+            assert_eq!(decoded.timestamp, event.timestamp);
+            assert_eq!(decoded.key, event.key);
+            assert_eq!(decoded.headers, event.headers);
+            assert_eq!(decoded.content, event.content);
+        }
+    }
+
+    #[test]
+    fn compressed_records_shrink() {
+        let event = test_event(&"compressible ".repeat(20));
+        let raw = encode_record(3, &event, Compression::None).unwrap();
+        assert!(encode_record(3, &event, Compression::Zstd).unwrap().len() < raw.len());
+        assert!(encode_record(3, &event, Compression::Lz4).unwrap().len() < raw.len());
+    }
+
+    #[test]
+    fn checksums_cover_length_and_record() {
+        let checksum = record_checksum(&3u64.to_be_bytes(), b"abc");
+        assert_eq!(checksum, crc32fast::hash(&[&3u64.to_be_bytes()[..], b"abc"].concat()));
+        assert_ne!(checksum, record_checksum(&4u64.to_be_bytes(), b"abc"));
+        assert_ne!(checksum, record_checksum(&3u64.to_be_bytes(), b"abd"));
+    }
+
+    #[test]
+    fn reads_legacy_log_files() {
+        let _storage = test_storage();
+        let mut contents:Vec<u8> = Vec::new();
+        for content in ["first", "second"] {
+            contents.extend_from_slice(&(content.len() as u64).to_be_bytes());
+            contents.extend_from_slice(content.as_bytes());
+        }
+        write_log_file("legacy-log", &contents);
+
+        let file = get_topic_file("legacy-log", &0).unwrap();
+        let header = read_segment_header(&file).unwrap();
+        assert_eq!((header.version, header.length), (1, 0));
+
+        let (event, next_position) = read_record(&file, &header, None, "legacy-log", 0, 0).unwrap();
+        assert_eq!(event.content, b"first");
+        assert_eq!(event.timestamp, 0);
+        assert_eq!(event.key, None);
+        assert_eq!(next_position, 13);
+        assert_eq!(event.eid, format_event_id("legacy-log", 13, 0));
+        assert_eq!(read_at("legacy-log", &0, 13).unwrap().content, b"second");
+    }
+
+    #[test]
+    fn reads_version_2_log_files() {
+        let _storage = test_storage();
+        let event = test_event("second version");
+        let mut contents = b"SFLG".to_vec();
+        contents.extend_from_slice(&2u32.to_be_bytes());
+        contents.extend_from_slice(&framed_record(&encode_event(&event)));
+        write_log_file("version-2-log", &contents);
+
+        let decoded = read_at("version-2-log", &0, 0).unwrap();
+        assert_eq!(decoded.content, event.content);
+        assert_eq!(decoded.key, event.key);
+        assert_eq!(decoded.eid, format_event_id("version-2-log", contents.len() as u64, 0));
+    }
+
+    #[test]
+    fn reads_version_3_log_files() {
+        let _storage = test_storage();
+        let topic = Topic::new("version-3-log".to_string()).unwrap();
+        topic.configure("compression", "zstd").unwrap();
+        let event = test_event(&"compressible ".repeat(20));
+        write(&topic.name, &event, false).unwrap();
+
+        let file = get_topic_file(&topic.name, &0).unwrap();
+        assert_eq!(read_segment_header(&file).unwrap().version, 3);
+        let decoded = read_at(&topic.name, &0, 0).unwrap();
+        assert_eq!(decoded.content, event.content);
+        assert_eq!(decoded.headers, event.headers);
+    }
+
+    #[test]
+    fn rejects_corrupt_records() {
+        let _storage = test_storage();
+        let mut contents = b"SFLG".to_vec();
+        contents.extend_from_slice(&2u32.to_be_bytes());
+        contents.extend_from_slice(&framed_record(&encode_event(&test_event("content"))));
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        write_log_file("corrupt-log", &contents);
+
+        // A bad checksum with a trustworthy length can be skipped
+        let error = read_at("corrupt-log", &0, 8).err().unwrap();
+        let record = error.downcast_ref::<CorruptRecord>().unwrap();
+        assert_eq!((record.log_file, record.log_offset), (0, 8));
+        assert_eq!(record.skip_eid, Some(format_event_id("corrupt-log", contents.len() as u64, 0)));
+
+        // A length past the end of the log file can't
+        contents[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        write_log_file("corrupt-log", &contents);
+        let error = read_at("corrupt-log", &0, 8).err().unwrap();
+        assert_eq!(error.downcast_ref::<CorruptRecord>().unwrap().skip_eid, None);
+    }
+
+    #[test]
+    fn updates_the_registry_record_of_the_topic() {
+        let _storage = test_storage();
+        for name in ["registry-a", "registry-b", "registry-cccc"] {
+            Topic::new(name.to_string()).unwrap();
+        }
+        let mut topic = Topic::hydrate("registry-b").unwrap();
+
+        // Deleting a topic moves the records after it
+        Topic::hydrate("registry-a").unwrap().delete().unwrap();
+        topic.bump().unwrap();
+
+        assert_eq!(Topic::hydrate("registry-b").unwrap().curr_log_file, 1);
+        let other = Topic::hydrate("registry-cccc").unwrap();
+        assert_eq!((other.first_log_file, other.curr_log_file), (0, 0));
+
+        topic.delete().unwrap();
+        assert!(update_curr_log_file_in_config(&topic).is_err());
+    }
+}
+
@@ -0,0 +1,108 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, Condvar}, thread, time::Duration};
+use anyhow::{Result, anyhow};
+use crate::configs::topics::sync_topic_file;
+
+// Batches fsyncs for topics running in interval mode. Writers block until a
+// sync that started after their write has completed.
+#[derive(Debug)]
+pub struct GroupCommit {
+    interval: Duration,
+    max_pending_bytes: u64,
+    state: Mutex<PendingSyncs>,
+    synced: Condvar,
+    syncing: Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct PendingSyncs {
+    files: HashSet<(String, u64)>,
+    bytes: u64,
+    started: u64,
+    completed: u64,
+    // Writers waiting on each sync generation & the generations whose fsync failed,
+    // dropped once their last writer has seen the result
+    waiting: HashMap<u64, usize>,
+    failed: HashSet<u64>,
+}
+
+impl GroupCommit {
+    pub fn new(interval: Duration, max_pending_bytes: u64) -> Self {
+        return GroupCommit {
+            interval,
+            max_pending_bytes,
+            state: Mutex::new(PendingSyncs::default()),
+            synced: Condvar::new(),
+            syncing: Mutex::new(()),
+        };
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        let group_commit = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(group_commit.interval);
+            group_commit.sync();
+        });
+    }
+
+    // Blocks until the written bytes are covered by an fsync
+    pub fn commit(&self, topic: &str, log_file: u64, bytes: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.files.insert((topic.to_owned(), log_file));
+        state.bytes += bytes;
+        // The next sync to start takes the file & covers the write
+        let target = state.started + 1;
+        *state.waiting.entry(target).or_default() += 1;
+        let sync_now = state.bytes >= self.max_pending_bytes;
+        drop(state);
+
+        if sync_now {
+            self.sync();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        while state.completed < target {
+            state = self.synced.wait(state).unwrap();
+        }
+        let failed = state.failed.contains(&target);
+        let writers = state.waiting.get_mut(&target).unwrap();
+        *writers -= 1;
+        if *writers == 0 {
+            state.waiting.remove(&target);
+            state.failed.remove(&target);
+        }
+        if failed {
+            return Err(anyhow!("Failed to sync topic {} to disk.", topic));
+        }
+        return Ok(());
+    }
+
+    fn sync(&self) {
+        // Syncs run one at a time so they complete in the order they started
+        let _syncing = self.syncing.lock().unwrap();
+
+        let mut state = self.state.lock().unwrap();
+        if state.files.is_empty() {
+            return;
+        }
+        let files = std::mem::take(&mut state.files);
+        state.bytes = 0;
+        state.started += 1;
+        let generation = state.started;
+        drop(state);
+
+        let mut failed = false;
+        for (topic, log_file) in files {
+            if let Err(e) = sync_topic_file(&topic, &log_file) {
+                println!("Error: failed to sync log file {} of topic {}: {}", log_file, topic, e);
+                failed = true;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.completed = generation;
+        if failed {
+            state.failed.insert(generation);
+        }
+        self.synced.notify_all();
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, fmt::Display, sync::Mutex, time::{Duration, Instant}};
+use anyhow::Result;
+use crate::subjects::settings::Settings;
+
+// Throttles writes with token buckets. Every producer has a bucket per topic it
+// writes to, & every topic has one shared by all of its producers. Buckets hold
+// a second's worth of writes or bytes, so short bursts still get through.
+#[derive(Debug)]
+pub struct RateLimits {
+    // Limits of every producer on topics that don't set their own
+    producer_requests_per_second: Option<u64>,
+    producer_bytes_per_second: Option<u64>,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    ProducerRequests(u64, String),
+    ProducerBytes(u64, String),
+    TopicRequests(String),
+    TopicBytes(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+// Buckets untouched for a second are full again, & no different from new ones
+const MAX_IDLE_BUCKETS:usize = 10000;
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        return Bucket {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        };
+    }
+
+    fn refill(&mut self, rate: u64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        // Changed settings apply to buckets that already exist
+        self.rate = rate;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.updated = now;
+    }
+
+    // How long until the bucket can pay the cost. Costs over the bucket's size are
+    // let through once it is full, & leave it in debt.
+    fn wait(&self, cost: u64) -> Option<Duration> {
+        let required = cost.min(self.rate) as f64;
+        if self.tokens >= required {
+            return None;
+        }
+        return Some(Duration::from_secs_f64((required - self.tokens) / self.rate as f64));
+    }
+}
+
+impl RateLimits {
+    pub fn new(producer_requests_per_second: Option<u64>, producer_bytes_per_second: Option<u64>) -> Self {
+        return RateLimits {
+            producer_requests_per_second,
+            producer_bytes_per_second,
+            buckets: Mutex::new(HashMap::new()),
+        };
+    }
+
+    // Takes a write of the given size from the producer's & the topic's buckets,
+    // or fails with how long to wait when any of them can't pay for it
+    pub fn acquire(&self, producer_offset: u64, topic: &str, bytes: u64, settings: &Settings) -> Result<()> {
+        let limits = [
+            (BucketKey::ProducerRequests(producer_offset, topic.to_owned()), settings.producer_requests_per_second.or(self.producer_requests_per_second), 1),
+            (BucketKey::ProducerBytes(producer_offset, topic.to_owned()), settings.producer_bytes_per_second.or(self.producer_bytes_per_second), bytes),
+            (BucketKey::TopicRequests(topic.to_owned()), settings.requests_per_second, 1),
+            (BucketKey::TopicBytes(topic.to_owned()), settings.bytes_per_second, bytes),
+        ];
+        let limits = limits.into_iter()
+                        .filter_map(|(key, rate, cost)| rate.map(|rate| (key, rate, cost)))
+                        .collect::<Vec<(BucketKey, u64, u64)>>();
+        if limits.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(1));
+        }
+
+        // Nothing is taken unless every bucket can pay
+        let mut retry_after = Duration::ZERO;
+        for (key, rate, cost) in &limits {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket::new(*rate, now));
+            bucket.refill(*rate, now);
+            if let Some(wait) = bucket.wait(*cost) {
+                retry_after = retry_after.max(wait);
+            }
+        }
+        if retry_after > Duration::ZERO {
+            return Err(RateLimited { retry_after }.into());
+        }
+        for (key, _, cost) in &limits {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= *cost as f64;
+            }
+        }
+        return Ok(());
+    }
+}
+
+// A write refused because the producer or its topic is over its rate limit
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl RateLimited {
+    // Retry-After only takes whole seconds
+    pub fn retry_after_secs(&self) -> u64 {
+        return self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    }
+}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Rate limit exceeded, retry in {} ms.", self.retry_after.as_millis().max(1));
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10, start);
+        assert_eq!(bucket.wait(10), None);
+
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(5), Some(Duration::from_millis(500)));
+        bucket.refill(10, start + Duration::from_millis(200));
+        assert!((bucket.tokens - 2.0).abs() < 1e-9);
+
+        // Buckets never hold more than a second's worth
+        bucket.refill(10, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+
+        // Changed rates apply from the next refill
+        bucket.refill(4, start + Duration::from_secs(61));
+        assert_eq!(bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn buckets_let_large_costs_through_when_full() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(100, start);
+        assert_eq!(bucket.wait(1000), None);
+        bucket.tokens -= 1000.0;
+        assert_eq!(bucket.wait(1), Some(Duration::from_secs_f64(901.0 / 100.0)));
+    }
+
+    #[test]
+    fn limits_producers_and_topics() {
+        let limits = RateLimits::new(Some(2), None);
+        let settings = Settings::default();
+        assert!(limits.acquire(0, "orders", 10, &settings).is_ok());
+        assert!(limits.acquire(0, "orders", 10, &settings).is_ok());
+        let error = limits.acquire(0, "orders", 10, &settings).err().unwrap();
+        assert!(error.downcast_ref::<RateLimited>().unwrap().retry_after > Duration::ZERO);
+
+        // Every producer & topic has its own bucket
+        assert!(limits.acquire(1, "orders", 10, &settings).is_ok());
+        assert!(limits.acquire(0, "payments", 10, &settings).is_ok());
+
+        let settings = Settings { bytes_per_second: Some(100), ..Settings::default() };
+        let limits = RateLimits::new(None, None);
+        assert!(limits.acquire(0, "orders", 60, &settings).is_ok());
+        assert!(limits.acquire(1, "orders", 60, &settings).is_err());
+    }
+
+    #[test]
+    fn retries_after_whole_seconds() {
+        assert_eq!(RateLimited { retry_after: Duration::from_millis(10) }.retry_after_secs(), 1);
+        assert_eq!(RateLimited { retry_after: Duration::from_millis(1500) }.retry_after_secs(), 2);
+    }
+}
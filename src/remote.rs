@@ -0,0 +1,291 @@
+use std::io::Read;
+use crate::{Commands, Subject, args, output_error, get_subject, get_topic, get_id, get_role, get_option, get_offset, get_data_token, get_data_topic, get_event, get_expires_at, get_grace};
+
+// Runs commands against a running server instead of the data directory. Topics,
+// producers, consumers & credentials are managed through the admin API with the
+// given credential, writes & reads use the same topic routes as any other producer
+// or consumer, with the data token as the bearer token.
+pub struct Remote {
+    server: String,
+    admin_token: Option<String>,
+}
+
+impl Remote {
+    pub fn new(server: String, admin_token: Option<String>) -> Self {
+        return Remote {
+            server: server.trim_end_matches('/').to_owned(),
+            admin_token,
+        };
+    }
+
+    pub fn run(&self, cmd: Commands) {
+        match cmd {
+            Commands::Cleanup => self.cleanup(),
+            Commands::Add => self.add(),
+            Commands::Delete => self.delete(),
+            Commands::Reroll => self.reroll(),
+            Commands::Stat => self.stat(),
+            Commands::List => self.list(),
+            Commands::Write => self.write(),
+            Commands::Read => self.read(),
+            Commands::Config => self.config(),
+            Commands::Seek => self.seek(),
+            Commands::Migrate => {
+                output_error("Migrations run against the data directory, not a server.");
+                std::process::exit(1);
+            },
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        return ureq::request(method, &format!("{}{}", self.server, path));
+    }
+
+    fn data_request(&self, method: &str, path: &str, token: &str) -> ureq::Request {
+        return self.request(method, path).set("Authorization", &format!("Bearer {}", token));
+    }
+
+    fn admin_request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.request(method, path);
+        match &self.admin_token {
+            Some(admin_token) => return request.set("Authorization", &format!("Bearer {}", admin_token)),
+            None => return request,
+        }
+    }
+
+    // Prints the response body of a successful admin request
+    fn print_admin(&self, request: ureq::Request, body: Option<&str>) {
+        let response = send(request, body.unwrap_or("").as_bytes());
+        let body = response.into_string().unwrap_or_else(|e| {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        });
+        println!("{}", body);
+    }
+
+    fn cleanup(&self) {
+        let topic = args()
+                        .nth(2)
+                        .unwrap_or_else(|| {
+                            output_error("Missing topic.");
+                            std::process::exit(1);
+                        })
+                        .to_lowercase();
+        self.print_admin(self.admin_request("POST", &format!("/admin/topics/{}/cleanup", topic)), None);
+    }
+
+    fn add(&self) {
+        let request = match get_subject() {
+            Subject::Producer => with_expiry(self.admin_request("POST", "/admin/producers").query("topic", &get_topic())),
+            Subject::Consumer => with_expiry(self.admin_request("POST", "/admin/consumers").query("topic", &get_topic())),
+            Subject::Topic => self.admin_request("POST", "/admin/topics").query("name", &get_topic()),
+            Subject::Credential => {
+                let (role, topic) = get_role();
+                let request = self.admin_request("POST", "/admin/credentials").query("role", &role.to_string());
+                match topic {
+                    Some(topic) => request.query("topic", &topic),
+                    None => request,
+                }
+            }
+        };
+        self.print_admin(request, None);
+    }
+
+    fn delete(&self) {
+        let path = match get_subject() {
+            Subject::Producer => format!("/admin/producers/{}", get_id()),
+            Subject::Consumer => format!("/admin/consumers/{}", get_id()),
+            Subject::Topic => format!("/admin/topics/{}", get_topic()),
+            Subject::Credential => format!("/admin/credentials/{}", get_id()),
+        };
+        self.print_admin(self.admin_request("DELETE", &path), None);
+    }
+
+    fn reroll(&self) {
+        let request = match get_subject() {
+            Subject::Producer => self.reroll_request(&format!("/admin/producers/{}/reroll", get_id())),
+            Subject::Consumer => self.reroll_request(&format!("/admin/consumers/{}/reroll", get_id())),
+            Subject::Credential => self.admin_request("POST", &format!("/admin/credentials/{}/reroll", get_id())),
+            Subject::Topic => {
+                output_error("Topics cannot be rerolled.");
+                std::process::exit(1);
+            },
+        };
+        self.print_admin(request, None);
+    }
+
+    fn reroll_request(&self, path: &str) -> ureq::Request {
+        let request = self.admin_request("POST", path).query("grace", &get_grace().to_string());
+        return with_expiry(request);
+    }
+
+    fn stat(&self) {
+        let path = match get_subject() {
+            Subject::Producer => format!("/admin/producers/{}", get_id()),
+            Subject::Consumer => format!("/admin/consumers/{}", get_id()),
+            Subject::Topic => format!("/admin/topics/{}", get_topic()),
+            Subject::Credential => format!("/admin/credentials/{}", get_id()),
+        };
+        self.print_admin(self.admin_request("GET", &path), None);
+    }
+
+    fn list(&self) {
+        let path = match get_subject() {
+            Subject::Producer => "/admin/producers",
+            Subject::Consumer => "/admin/consumers",
+            Subject::Topic => "/admin/topics",
+            Subject::Credential => "/admin/credentials",
+        };
+        self.print_admin(self.admin_request("GET", path), None);
+    }
+
+    fn config(&self) {
+        match get_subject() {
+            Subject::Topic => {}
+            _ => {
+                output_error("Only topics can be configured.");
+                std::process::exit(1);
+            },
+        }
+        let topic = get_topic();
+        let settings = args().skip(4).collect::<Vec<String>>();
+        if settings.is_empty() {
+            self.print_admin(self.admin_request("GET", &format!("/admin/topics/{}/settings", topic)), None);
+            return;
+        }
+        if settings.iter().any(|setting| !setting.contains('=')) {
+            output_error("Settings must be formatted as key=value.");
+            std::process::exit(1);
+        }
+        self.print_admin(self.admin_request("PATCH", &format!("/admin/topics/{}/settings", topic)), Some(&settings.join("\n")));
+    }
+
+    fn seek(&self) {
+        match get_subject() {
+            Subject::Consumer => {}
+            _ => {
+                output_error("Only consumers can seek.");
+                std::process::exit(1);
+            },
+        }
+        let mut request = self.admin_request("POST", &format!("/admin/consumers/{}/seek", get_id()));
+        if let Some(topic) = get_data_topic() {
+            request = request.query("topic", &topic);
+        }
+        let args = args().collect::<Vec<String>>();
+        let request = if let Some(offset) = get_offset() {
+            request.query("offset", &offset.to_string())
+        } else if let Some(time) = get_option(&["-t", "--time"]) {
+            request.query("timestamp", &time)
+        } else if let Some(event_id) = get_option(&["-e", "--eid"]) {
+            request.query("eid", &event_id)
+        } else if args.iter().any(|arg| arg == "--earliest") {
+            request.query("position", "earliest")
+        } else if args.iter().any(|arg| arg == "--latest") {
+            request.query("position", "latest")
+        } else {
+            output_error("Missing seek target. Use --offset, --time, --eid, --earliest or --latest.");
+            std::process::exit(1);
+        };
+        self.print_admin(request, None);
+    }
+
+    fn data_topic(&self) -> String {
+        return get_data_topic().unwrap_or_else(|| {
+            output_error("Missing topic. Pass it with --topic.");
+            std::process::exit(1);
+        }).to_lowercase();
+    }
+
+    fn write(&self) {
+        let token = get_data_token();
+        let topic = self.data_topic();
+        let event = get_event();
+        let mut request = self.data_request("PUT", &format!("/topics/{}/events", topic), &token);
+        if let Some(key) = &event.key {
+            request = request.set("SF-Key", key);
+        }
+        for (name, value) in &event.headers {
+            request = request.set(&format!("SF-Header-{}", name), value);
+        }
+        send(request, &event.content);
+        println!("{{ \"success\": true  }}");
+    }
+
+    fn read(&self) {
+        let token = get_data_token();
+        let topic = self.data_topic();
+        let offset = get_offset();
+        let path = match offset {
+            Some(offset) => format!("/topics/{}/events/{}", topic, offset),
+            None => format!("/topics/{}/events", topic),
+        };
+        let response = match self.data_request("GET", &path, &token).call() {
+            // Move past a corrupt record like the local read does, then report it
+            Err(ureq::Error::Status(422, response)) => {
+                if let (None, Some(skip_eid)) = (offset, response.header("SF-Event-ID")) {
+                    send(self.data_request("POST", &format!("/topics/{}/events/{}", topic, skip_eid), &token), &[]);
+                }
+                eprintln!("{}", response.into_string().unwrap_or_default());
+                std::process::exit(1);
+            }
+            result => send_result(result),
+        };
+        if response.status() == 204 {
+            output_error("EOF");
+            std::process::exit(1);
+        }
+        let event_id = response.header("SF-Event-ID").map(|eid| eid.to_owned());
+        let mut content:Vec<u8> = Vec::new();
+        response.into_reader().read_to_end(&mut content).unwrap_or_else(|e| {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        });
+
+        // Reads by offset don't move the consumer, others are committed like the
+        // local read does. Lossy servers commit on read & send no event ID.
+        if let (None, Some(event_id)) = (offset, &event_id) {
+            send(self.data_request("POST", &format!("/topics/{}/events/{}", topic, event_id), &token), &[]);
+        }
+
+        let content = String::from_utf8(content).unwrap_or_else(|e| {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        });
+        match event_id {
+            Some(event_id) => println!("{}: {}", event_id, content.escape_default()),
+            None => println!("{}", content.escape_default()),
+        }
+    }
+}
+
+fn with_expiry(request: ureq::Request) -> ureq::Request {
+    match get_expires_at() {
+        Some(expires_at) => return request.query("expires", &expires_at.to_string()),
+        None => return request,
+    }
+}
+
+// Sends the request, exiting with the server's error response when it fails
+fn send(request: ureq::Request, body: &[u8]) -> ureq::Response {
+    let result = match body.is_empty() {
+        true => request.call(),
+        false => request.send_bytes(body),
+    };
+    return send_result(result);
+}
+
+fn send_result(result: Result<ureq::Response, ureq::Error>) -> ureq::Response {
+    match result {
+        Ok(response) => return response,
+        Err(ureq::Error::Status(_, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            eprintln!("{}", body);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            output_error(&e.to_string());
+            std::process::exit(1);
+        }
+    }
+}
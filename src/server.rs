@@ -1,98 +1,417 @@
+#![allow(clippy::needless_return)]
+
 mod subjects;
 mod configs;
+mod durability;
+mod appender;
+mod admin;
+mod limits;
 
-use std::env;
+use std::{env, collections::HashMap, sync::Arc, thread, time::Duration};
 
-use subjects::consumer::Consumer;
+use subjects::consumer::{Consumer, SeekTarget};
 use subjects::producer::Producer;
-use subjects::event::Event;
+use subjects::event::{Event, parse_timestamp};
+use subjects::topic::Topic;
+use subjects::settings::{Durability, CleanupPolicy};
+use subjects::credential::Credential;
+use durability::GroupCommit;
+use appender::Appenders;
+use limits::{RateLimits, RateLimited};
+use configs::errors::{CorruptRecord, InvalidEventId, OffsetOutOfRange, StaleEventId, UnknownTopic, TopicExists, InvalidToken, TopicForbidden, InvalidInput, MessageTooLarge, UnknownId};
+use configs::topics::get_topics;
+use configs::{init_data_dir, data_dir};
+use configs::storage::{set_storage, MemoryStorage};
+use configs::keys::hash_plain_keys;
 
-use actix_web::{get, put, post, App, HttpServer, web::{self, Bytes}, Result, HttpResponse, http::StatusCode};
+use actix_web::{get, put, post, App, HttpServer, HttpRequest, web::{self, Bytes}, Result, HttpResponse, http::StatusCode};
 
 #[derive(Debug, Clone)]
 struct Config {
     pub lossy: bool,
+    pub durability: Durability,
+    pub group_commit: Arc<GroupCommit>,
+    pub appenders: Arc<Appenders>,
+    pub max_message_bytes: u64,
+    pub rate_limits: Arc<RateLimits>,
 }
 
-fn write_data(token: &String, content: &[u8]) -> anyhow::Result<()> {
-    let producer = Producer::hydrate(&token)?;
-    producer.write(content)?;
+fn write_data(token: &str, topic: Option<&str>, event: Event, config: &Config) -> anyhow::Result<()> {
+    // Path-token routes carry no topic, the token's own topic is implied
+    let producer = Producer::hydrate(token)?.for_topic(topic)?;
+    let settings = Topic::hydrate(&producer.topic)?.settings()?;
+    let durability = settings.durability.unwrap_or(config.durability);
+    let bytes = event.content.len() as u64;
+    settings.check_message_size(bytes, config.max_message_bytes)?;
+    config.rate_limits.acquire(producer.offset, &producer.topic, bytes, &settings)?;
+    let log_file = config.appenders.append(&producer.topic, event, durability == Durability::Always)?;
+    if durability == Durability::Interval {
+        config.group_commit.commit(&producer.topic, log_file, bytes)?;
+    }
     return Ok(());
 }
 
-fn read_data(token: &String, bump: bool) -> anyhow::Result<Event> {
-    let mut consumer = Consumer::hydrate(&token)?;
+fn hydrate_consumer(token: &str, topic: Option<&str>) -> anyhow::Result<Consumer> {
+    let consumer = Consumer::hydrate(token)?.for_topic(topic)?;
+    return Ok(consumer);
+}
+
+fn read_data(token: &str, topic: Option<&str>, bump: bool) -> anyhow::Result<Event> {
+    let mut consumer = hydrate_consumer(token, topic)?;
     let data = consumer.read(bump)?;
     return Ok(data);
 }
 
-fn bump(token: &String, event_id: &String) -> anyhow::Result<()> {
-    let mut consumer = Consumer::hydrate(&token)?;
-    consumer.bump(event_id)?;
+fn read_offset_data(token: &str, topic: Option<&str>, offset: u64) -> anyhow::Result<Event> {
+    let consumer = hydrate_consumer(token, topic)?;
+    let data = consumer.read_offset(offset)?;
+    return Ok(data);
+}
+
+fn seek_data(token: &str, topic: Option<&str>, target: &SeekTarget) -> anyhow::Result<()> {
+    let mut consumer = hydrate_consumer(token, topic)?;
+    consumer.seek(target)?;
     return Ok(());
 }
 
-#[get("/{token}")]
-async fn read(token: web::Path<String>, web_data: web::Data<Config>) -> Result<HttpResponse> {
+fn bump(token: &str, topic: Option<&str>, event_id: &str, force: bool) -> anyhow::Result<()> {
+    let mut consumer = hydrate_consumer(token, topic)?;
+    consumer.bump(event_id, force)?;
+    return Ok(());
+}
+
+fn error_status(e: &anyhow::Error) -> StatusCode {
+    if e.is::<InvalidEventId>() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    if e.is::<OffsetOutOfRange>() {
+        return StatusCode::RANGE_NOT_SATISFIABLE;
+    }
+    if e.is::<StaleEventId>() || e.is::<TopicExists>() {
+        return StatusCode::CONFLICT;
+    }
+    if e.is::<UnknownTopic>() || e.is::<UnknownId>() {
+        return StatusCode::NOT_FOUND;
+    }
+    if e.is::<InvalidToken>() {
+        return StatusCode::UNAUTHORIZED;
+    }
+    if e.is::<TopicForbidden>() {
+        return StatusCode::FORBIDDEN;
+    }
+    if e.is::<InvalidInput>() {
+        return StatusCode::BAD_REQUEST;
+    }
+    if e.is::<MessageTooLarge>() {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    if e.is::<RateLimited>() {
+        return StatusCode::TOO_MANY_REQUESTS;
+    }
+    return StatusCode::INTERNAL_SERVER_ERROR;
+}
+
+fn recover_topics() -> anyhow::Result<()> {
+    for topic in get_topics()? {
+        let dropped_bytes = topic.recover()?;
+        if dropped_bytes > 0 {
+            println!("Recovered topic {}: dropped {} bytes of incomplete records from log file {}", topic.name, dropped_bytes, topic.curr_log_file);
+        }
+    }
+    return Ok(());
+}
+
+// Runs forever, applying every topic's retention or compaction settings on an interval
+fn enforce_retention(interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let topics = get_topics().unwrap_or_else(|e| {
+            println!("Retention error: {}", e);
+            return Vec::new();
+        });
+        for mut topic in topics {
+            let compact = topic.settings().is_ok_and(|settings| settings.cleanup_policy == Some(CleanupPolicy::Compact));
+            if compact {
+                match topic.compact() {
+                    Ok(compaction) => {
+                        for (log_file, position) in compaction.skipped {
+                            println!("Compaction skipped log file {} of topic {}: corrupt record at offset {}", log_file, topic.name, position);
+                        }
+                        if compaction.dropped > 0 {
+                            println!("Compaction dropped {} events from topic {}", compaction.dropped, topic.name);
+                        }
+                    }
+                    Err(e) => println!("Compaction error on topic {}: {}", topic.name, e),
+                }
+                continue;
+            }
+            match topic.enforce_retention() {
+                Ok(0) => {}
+                Ok(deleted) => println!("Retention deleted {} log files from topic {}", deleted, topic.name),
+                Err(e) => println!("Retention error on topic {}: {}", topic.name, e),
+            }
+        }
+    }
+}
+
+// Token of an Authorization: Bearer <token> header
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    return req.headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.to_owned());
+}
+
+// Storage calls wait on file locks & disk I/O, so they run on the blocking thread pool
+// instead of stalling the worker's other requests
+pub async fn blocking<T: Send + 'static>(call: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> anyhow::Result<T> {
+    return web::block(call).await.unwrap_or_else(|e| Err(anyhow::anyhow!(e.to_string())));
+}
+
+fn missing_token_response() -> HttpResponse {
+    return HttpResponse::build(StatusCode::UNAUTHORIZED).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", "false", "Missing token. Send it as Authorization: Bearer <token>."));
+}
+
+async fn read_response(token: String, topic: Option<String>, web_data: &Config) -> HttpResponse {
     let mut error:String = String::new();
     let mut success = "true";
-    let data = read_data(&token, web_data.lossy).unwrap_or_else(|e| {
+    let mut corrupt:Option<CorruptRecord> = None;
+    let mut status = StatusCode::INTERNAL_SERVER_ERROR;
+    let lossy = web_data.lossy;
+    let data = blocking(move || read_data(&token, topic.as_deref(), lossy)).await.unwrap_or_else(|e| {
         error = e.to_string();
         success = "false";
-        return Event{eid: String::new(), content: Vec::new()};
+        status = error_status(&e);
+        corrupt = e.downcast::<CorruptRecord>().ok();
+        return Event::new(Vec::new(), None, Vec::new());
     });
     if success == "false" {
+        if let Some(corrupt) = corrupt {
+            let mut response = HttpResponse::build(StatusCode::UNPROCESSABLE_ENTITY);
+            response.insert_header(("SF-Corrupt-Record", format!("{}-{}", corrupt.log_offset, corrupt.log_file)));
+            if let (false, Some(skip_eid)) = (web_data.lossy, corrupt.skip_eid) {
+                response.insert_header(("SF-Event-ID", skip_eid));
+            }
+            return response.body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
+        }
         if error == "EOF" {
-            return Ok(HttpResponse::build(StatusCode::NO_CONTENT)
+            return HttpResponse::build(StatusCode::NO_CONTENT)
                       .content_type("application/octet-stream")
-                      .body(data.content));
+                      .body(data.content);
         }
-        return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+        if status != StatusCode::INTERNAL_SERVER_ERROR {
+            return HttpResponse::build(status).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
+        }
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
                   .content_type("application/octet-stream")
-                  .body(data.content));
+                  .body(data.content);
     }
     
-    if web_data.lossy {
-        return Ok(HttpResponse::build(StatusCode::OK)
-                    .content_type("application/octet-stream")
-                    .body(data.content));
+    return event_response(data, !web_data.lossy);
+}
+
+#[get("/{token}")]
+async fn read(token: web::Path<String>, web_data: web::Data<Config>) -> Result<HttpResponse> {
+    return Ok(read_response(token.into_inner(), None, &web_data).await);
+}
+
+#[get("/topics/{topic}/events")]
+async fn read_topic(req: HttpRequest, topic: web::Path<String>, web_data: web::Data<Config>) -> Result<HttpResponse> {
+    let token = match bearer_token(&req) {
+        Some(token) => token,
+        None => return Ok(missing_token_response()),
+    };
+    return Ok(read_response(token, Some(topic.into_inner()), &web_data).await);
+}
+
+async fn read_offset_response(token: String, topic: Option<String>, offset: u64) -> HttpResponse {
+    let mut error:String = String::new();
+    let mut success = "true";
+    let mut status = StatusCode::OK;
+    let data = blocking(move || read_offset_data(&token, topic.as_deref(), offset)).await.unwrap_or_else(|e| {
+        error = e.to_string();
+        success = "false";
+        // Nothing has been written at the next offset yet
+        status = if error == "EOF" { StatusCode::NOT_FOUND } else { error_status(&e) };
+        return Event::new(Vec::new(), None, Vec::new());
+    });
+    if success == "false" {
+        return HttpResponse::build(status).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
     }
-    return Ok(HttpResponse::build(StatusCode::OK)
-                .content_type("application/octet-stream")
-                .insert_header(("SF-Event-ID", data.eid))
-                .body(data.content));
+    return event_response(data, false);
 }
 
-#[post("/{token}/{event_id}")]
-async fn post(tokens: web::Path<(String, String)>, data: web::Data<Config>) -> Result<HttpResponse> {
+#[get("/{token}/{offset}")]
+async fn read_offset(path: web::Path<(String, u64)>) -> Result<HttpResponse> {
+    let (token, offset) = path.into_inner();
+    return Ok(read_offset_response(token, None, offset).await);
+}
+
+#[get("/topics/{topic}/events/{offset}")]
+async fn read_topic_offset(req: HttpRequest, path: web::Path<(String, u64)>) -> Result<HttpResponse> {
+    let token = match bearer_token(&req) {
+        Some(token) => token,
+        None => return Ok(missing_token_response()),
+    };
+    let (topic, offset) = path.into_inner();
+    return Ok(read_offset_response(token, Some(topic), offset).await);
+}
+
+fn event_response(data: Event, include_eid: bool) -> HttpResponse {
+    let mut response = HttpResponse::build(StatusCode::OK);
+    response.content_type("application/octet-stream");
+    response.insert_header(("SF-Timestamp", data.timestamp.to_string()));
+    if let Some(key) = &data.key {
+        response.insert_header(("SF-Key", key.as_str()));
+    }
+    for (name, value) in &data.headers {
+        response.append_header((format!("SF-Header-{}", name), value.as_str()));
+    }
+    if include_eid {
+        response.insert_header(("SF-Event-ID", data.eid));
+    }
+    return response.body(data.content);
+}
+
+fn parse_seek_target(query: &HashMap<String, String>) -> anyhow::Result<SeekTarget> {
+    if let Some(offset) = query.get("offset") {
+        return Ok(SeekTarget::Offset(offset.parse::<u64>()?));
+    }
+    if let Some(timestamp) = query.get("timestamp") {
+        return Ok(SeekTarget::Timestamp(parse_timestamp(timestamp)?));
+    }
+    if let Some(event_id) = query.get("eid") {
+        return Ok(SeekTarget::EventId(event_id.to_owned()));
+    }
+    match query.get("position").map(|position| position.as_str()) {
+        Some("earliest") => return Ok(SeekTarget::Earliest),
+        Some("latest") => return Ok(SeekTarget::Latest),
+        _ => return Err(anyhow::anyhow!("Missing seek target. Use offset, timestamp, eid or position=earliest|latest.")),
+    }
+}
+
+async fn seek_response(token: String, topic: Option<String>, query: &HashMap<String, String>) -> HttpResponse {
+    let mut error:String = String::new();
+    let mut success = "true";
+    let target = match parse_seek_target(query) {
+        Ok(target) => target,
+        Err(e) => return HttpResponse::build(StatusCode::BAD_REQUEST).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", "false", e)),
+    };
+    let mut status = StatusCode::OK;
+    blocking(move || seek_data(&token, topic.as_deref(), &target)).await.unwrap_or_else(|e| {
+        error = e.to_string();
+        success = "false";
+        status = error_status(&e);
+    });
+    if success == "false" {
+        return HttpResponse::build(status).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
+    }
+    return HttpResponse::build(StatusCode::OK).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
+}
+
+#[post("/{token}/seek")]
+async fn seek(token: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    return Ok(seek_response(token.into_inner(), None, &query).await);
+}
+
+#[post("/topics/{topic}/seek")]
+async fn seek_topic(req: HttpRequest, topic: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let token = match bearer_token(&req) {
+        Some(token) => token,
+        None => return Ok(missing_token_response()),
+    };
+    return Ok(seek_response(token, Some(topic.into_inner()), &query).await);
+}
+
+async fn commit_response(token: String, topic: Option<String>, event_id: String, query: &HashMap<String, String>, data: &Config) -> HttpResponse {
     if data.lossy {
-        return Ok(HttpResponse::build(StatusCode::METHOD_NOT_ALLOWED).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", "false", "Sailfish is running in lossy mode.")));
+        return HttpResponse::build(StatusCode::METHOD_NOT_ALLOWED).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", "false", "Sailfish is running in lossy mode."));
     }
     let mut error:String = String::new();
     let mut success = "true";
-    bump(&tokens.0, &tokens.1).unwrap_or_else(|e| {
+    let mut status = StatusCode::OK;
+    // Moving a consumer backwards must be explicit
+    let force = query.get("force").is_some_and(|force| force == "true");
+    blocking(move || bump(&token, topic.as_deref(), &event_id, force)).await.unwrap_or_else(|e| {
         error = e.to_string();
         success = "false";
+        status = error_status(&e);
     });
     if success == "false" {
-        return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error)));
+        return HttpResponse::build(status).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
     }
-    return Ok(HttpResponse::build(StatusCode::OK).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error)));
+    return HttpResponse::build(StatusCode::OK).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
 }
 
-#[put("/{token}")]
-async fn write(bytes: Bytes, token: web::Path<String>) -> Result<HttpResponse> {
+#[post("/{token}/{event_id}")]
+async fn post(tokens: web::Path<(String, String)>, query: web::Query<HashMap<String, String>>, data: web::Data<Config>) -> Result<HttpResponse> {
+    let (token, event_id) = tokens.into_inner();
+    return Ok(commit_response(token, None, event_id, &query, &data).await);
+}
+
+#[post("/topics/{topic}/events/{event_id}")]
+async fn commit_topic(req: HttpRequest, path: web::Path<(String, String)>, query: web::Query<HashMap<String, String>>, data: web::Data<Config>) -> Result<HttpResponse> {
+    let token = match bearer_token(&req) {
+        Some(token) => token,
+        None => return Ok(missing_token_response()),
+    };
+    let (topic, event_id) = path.into_inner();
+    return Ok(commit_response(token, Some(topic), event_id, &query, &data).await);
+}
+
+// Builds an event from the body plus the SF-Key & SF-Header-* request headers
+fn parse_event(req: &HttpRequest, body: Vec<u8>) -> anyhow::Result<Event> {
+    let mut key:Option<String> = None;
+    let mut headers:Vec<(String, String)> = Vec::new();
+    for (name, value) in req.headers() {
+        let name = name.as_str();
+        if name == "sf-key" {
+            key = Some(value.to_str()?.to_owned());
+        } else if let Some(name) = name.strip_prefix("sf-header-") {
+            headers.push((name.to_owned(), value.to_str()?.to_owned()));
+        }
+    }
+    return Ok(Event::new(body, key, headers));
+}
+
+async fn write_response(req: &HttpRequest, bytes: Bytes, token: String, topic: Option<String>, web_data: web::Data<Config>) -> HttpResponse {
     let mut error:String = String::new();
     let mut success = "true";
-    let body = bytes.to_vec();
-    write_data(&token, &body).unwrap_or_else(|e| {
+    let event = match parse_event(req, bytes.to_vec()) {
+        Ok(event) => event,
+        Err(e) => return HttpResponse::build(StatusCode::BAD_REQUEST).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", "false", e)),
+    };
+    let mut status = StatusCode::ACCEPTED;
+    let mut retry_after:Option<u64> = None;
+    blocking(move || write_data(&token, topic.as_deref(), event, &web_data)).await.unwrap_or_else(|e| {
         error = e.to_string();
         success = "false";
+        status = error_status(&e);
+        retry_after = e.downcast_ref::<RateLimited>().map(|limited| limited.retry_after_secs());
     });
     if success == "false" {
-        return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error)));
+        let mut response = HttpResponse::build(status);
+        if let Some(retry_after) = retry_after {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
+        return response.body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
     }
-    return Ok(HttpResponse::build(StatusCode::ACCEPTED).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error)));
+    return HttpResponse::build(StatusCode::ACCEPTED).body(format!("{{ \"success\": \"{}\",\"error\": \"{}\" }}", success, error));
+}
+
+#[put("/{token}")]
+async fn write(req: HttpRequest, bytes: Bytes, token: web::Path<String>, web_data: web::Data<Config>) -> Result<HttpResponse> {
+    return Ok(write_response(&req, bytes, token.into_inner(), None, web_data).await);
+}
+
+#[put("/topics/{topic}/events")]
+async fn write_topic(req: HttpRequest, bytes: Bytes, topic: web::Path<String>, web_data: web::Data<Config>) -> Result<HttpResponse> {
+    let token = match bearer_token(&req) {
+        Some(token) => token,
+        None => return Ok(missing_token_response()),
+    };
+    return Ok(write_response(&req, bytes, token, Some(topic.into_inner()), web_data).await);
 }
 
 #[actix_web::main]
@@ -101,6 +420,15 @@ async fn main() -> std::io::Result<()> {
     let mut port:u16 = 8080;
     let mut host = "127.0.0.1";
     let mut lossy = false;
+    let mut durability = Durability::Always;
+    let mut sync_interval:u64 = 100;
+    let mut sync_bytes:u64 = 1000000;
+    let mut retention_interval:u64 = 60000;
+    let mut memory_storage = false;
+    let mut path_tokens = false;
+    let mut max_message_bytes:u64 = 1048576;
+    let mut producer_requests_per_second:Option<u64> = None;
+    let mut producer_bytes_per_second:Option<u64> = None;
 
     let args = env::args().collect::<Vec<String>>();
     for i in 1..args.len() {
@@ -117,21 +445,139 @@ async fn main() -> std::io::Result<()> {
             "-l" | "--lossy" => {
                 lossy = true;
             }
+            "-d" | "--durability" => {
+                durability = args[i+1].parse::<Durability>().unwrap_or_else(|e| {
+                    println!("{}", e);
+                    std::process::exit(1);
+                });
+            }
+            "--sync-interval" => {
+                sync_interval = args[i+1].parse::<u64>().unwrap_or_else(|_| {
+                    println!("Invalid sync interval. Sync interval must be a number of milliseconds.");
+                    std::process::exit(1);
+                });
+            }
+            "--sync-bytes" => {
+                sync_bytes = args[i+1].parse::<u64>().unwrap_or_else(|_| {
+                    println!("Invalid sync threshold. Sync threshold must be a number of bytes.");
+                    std::process::exit(1);
+                });
+            }
+            "--retention-interval" => {
+                retention_interval = args[i+1].parse::<u64>().unwrap_or_else(|_| {
+                    println!("Invalid retention interval. Retention interval must be a number of milliseconds.");
+                    std::process::exit(1);
+                });
+            }
+            // Largest event accepted, topics can only lower it
+            "--max-message-bytes" => {
+                max_message_bytes = args[i+1].parse::<u64>().unwrap_or_else(|_| {
+                    println!("Invalid maximum message size. Maximum message size must be a number of bytes.");
+                    std::process::exit(1);
+                });
+            }
+            // Limits of every producer on topics that don't set their own, unlimited by default
+            "--producer-requests-per-second" => {
+                producer_requests_per_second = Some(args[i+1].parse::<u64>().ok().filter(|rate| *rate > 0).unwrap_or_else(|| {
+                    println!("Invalid producer request rate. Producer request rate must be a positive number of writes.");
+                    std::process::exit(1);
+                }));
+            }
+            "--producer-bytes-per-second" => {
+                producer_bytes_per_second = Some(args[i+1].parse::<u64>().ok().filter(|rate| *rate > 0).unwrap_or_else(|| {
+                    println!("Invalid producer byte rate. Producer byte rate must be a positive number of bytes.");
+                    std::process::exit(1);
+                }));
+            }
+            // Also serve the old routes with the token in the path, e.g. PUT /{token},
+            // until every client sends its token as a bearer token
+            "--path-tokens" => {
+                path_tokens = true;
+            }
+            "--storage" => {
+                memory_storage = match args[i+1].as_str() {
+                    "file" => false,
+                    "memory" => true,
+                    _ => {
+                        println!("Invalid storage. Valid storages are file and memory.");
+                        std::process::exit(1);
+                    }
+                };
+            }
             _ => {}
         }
     }
 
+    init_data_dir(&args).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(1);
+    });
+
+    // Nothing is written to the data directory, everything is lost on shutdown
+    if memory_storage {
+        set_storage(Box::new(MemoryStorage::new())).unwrap_or_else(|e| {
+            println!("{}", e);
+            std::process::exit(1);
+        });
+    }
+
+    recover_topics().unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    // The first start creates an admin credential, more can be added through the admin API
+    let admin = Credential::bootstrap().unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        std::process::exit(1);
+    });
+    if let Some(admin) = admin {
+        println!("Created admin credential {}", admin.token());
+    }
+
+    // Registries from before keys were hashed are hashed in place
+    let hashed = hash_plain_keys().unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        std::process::exit(1);
+    });
+    if hashed > 0 {
+        println!("Hashed {} plain keys", hashed);
+    }
+
+    let group_commit = Arc::new(GroupCommit::new(Duration::from_millis(sync_interval), sync_bytes));
+    group_commit.start();
+
+    thread::spawn(move || enforce_retention(Duration::from_millis(retention_interval)));
+
     let config = web::Data::new(Config {
         lossy,
+        durability,
+        group_commit,
+        appenders: Arc::new(Appenders::new()),
+        max_message_bytes,
+        rate_limits: Arc::new(RateLimits::new(producer_requests_per_second, producer_bytes_per_second)),
     });
 
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::PayloadConfig::new(usize::MAX))
+            // Bodies over the maximum message size are refused before they are read
+            .app_data(web::PayloadConfig::new(usize::try_from(max_message_bytes).unwrap_or(usize::MAX)))
             .app_data(config.clone())
-            .service(read)
-            .service(write)
-            .service(post)
+            .configure(admin::routes)
+            .service(read_topic)
+            .service(read_topic_offset)
+            .service(write_topic)
+            .service(seek_topic)
+            .service(commit_topic)
+            .configure(|cfg| {
+                if path_tokens {
+                    cfg.service(read)
+                        .service(read_offset)
+                        .service(write)
+                        .service(seek)
+                        .service(post);
+                }
+            })
     })
     .bind((host, port))
     .unwrap_or_else(|e| {
@@ -139,6 +585,10 @@ async fn main() -> std::io::Result<()> {
         std::process::exit(1);
     });
 
-    println!("Listening on {}:{}", host, port);
+    if memory_storage {
+        println!("Listening on {}:{} with in-memory storage", host, port);
+    } else {
+        println!("Listening on {}:{} with data directory {}", host, port, data_dir());
+    }
     server.run().await
 }
@@ -1,32 +1,62 @@
 #![allow(unused)]
 
 use std::fmt::Display;
-use crate::configs::{topics::{topic_exists, read}, consumers::{add_consumer_to_config, get_consumer, delete_consumer, reroll_consumer_key, update_consumer_in_config, consumers_exists, create_consumer_file}};
-use super::{keys::generate_key, topic::Topic, event::Event};
+use crate::configs::{storage::lock_topic, errors::{CorruptRecord, StaleEventId, UnknownTopic, InvalidToken, InvalidInput, TopicForbidden, UnknownId}, indexes::{find_offset, find_timestamp, resolve_position, original_position}, topics::{topic_exists, read, read_at, get_topic_file}, consumers::{add_consumer_to_config, get_consumer, delete_consumer, reroll_consumer_key, update_consumer_in_config, consumers_exists, create_consumer_file, get_or_add_consumer_position, list_consumers}};
+use super::{keys::{generate_key, verify_key_at, expiry_json, KeyExpiry, KEY_LENGTH}, topic::{Topic, TopicGrant}, event::{Event, now, format_event_id, parse_event_id}};
 use anyhow::{Result,anyhow};
 
+pub enum SeekTarget {
+    // Message offset
+    Offset(u64),
+    // First event at or after the timestamp (milliseconds since the Unix epoch)
+    Timestamp(u64),
+    // First retained event
+    Earliest,
+    // End of the topic, only events written after the seek are read
+    Latest,
+    // Position right after the event, like committing it
+    EventId(String),
+}
+
+#[derive(Clone)]
 pub struct Consumer {
+    // A TopicGrant, which is just the topic for single topic consumers. Consumers of
+    // several topics keep a position record per topic, see for_topic.
     pub topic: String,
     pub log_file: u64,
     pub log_offset: u64,
     pub offset: u64,
+    // Only known right after creating, hydrating or rerolling, registries store a hash
     pub key: String,
+    pub stored_key: [u8; KEY_LENGTH],
+    // None for tokens created before tokens could expire
+    pub expiry: Option<KeyExpiry>,
 }
 
 impl Consumer {
-    pub fn new(topic: String) -> Result<Self> {
-        let topic = topic.to_lowercase();
-        if !topic_exists(&topic){
-            return Err(anyhow!("Topic {} has not been created yet.", topic));
+    // The topic can also grant several topics, see TopicGrant. Tokens expire at the
+    // given time, in milliseconds since the Unix epoch.
+    pub fn new(topic: String, expires_at: Option<u64>) -> Result<Self> {
+        let grant = TopicGrant::parse(&topic)?;
+        for topic in grant.topics() {
+            if !topic_exists(topic){
+                return Err(UnknownTopic { topic: topic.to_owned() }.into());
+            }
         }
-        let topic = Topic::hydrate(&topic)?;
+        // Only single topic consumers read from their own record
+        let first_log_file = match grant.single() {
+            Some(topic) => Topic::hydrate(topic)?.first_log_file,
+            None => 0,
+        };
         let key = generate_key();
         let mut consumer = Consumer{
-            topic: topic.name,
-            log_file: topic.first_log_file,
+            topic: grant.to_string(),
+            log_file: first_log_file,
             log_offset: 0,
             offset: 0,
             key,
+            stored_key: [0u8; KEY_LENGTH],
+            expiry: Some(KeyExpiry::new(expires_at)),
         };
         if !consumers_exists() {
             create_consumer_file();
@@ -35,55 +65,156 @@ impl Consumer {
         return Ok(consumer);
     }
 
-    pub fn hydrate(token: &String) -> Result<Self> {
+    pub fn hydrate(token: &str) -> Result<Self> {
         let offset = token.split_once("-").unwrap_or(("",""));
-        if offset.0 == "" || offset.1 == "" {
-            return Err(anyhow!("Invalid token format."));
+        if offset.0.is_empty() || offset.1.is_empty() {
+            return Err(InvalidInput { reason: "Invalid token format.".to_string() }.into());
         }
-        let offset:u64 = offset.0.parse()?;
-        let consumer = get_consumer(offset)?;
-        if consumer.key != token.split_once("-").unwrap_or(("","")).1 {
-            return Err(anyhow!("Unauthorized."));
+        let offset:u64 = offset.0.parse().map_err(|_| InvalidInput { reason: "Invalid token format.".to_string() })?;
+        // Offsets past the end of the registry, or without a registry, belong to no one
+        let mut consumer = get_consumer(offset).map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if matches!(io.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::NotFound) => InvalidToken.into(),
+            _ => e,
+        })?;
+        let key = token.split_once("-").unwrap_or(("","")).1;
+        if !verify_key_at(&consumer.stored_key, consumer.expiry.as_ref(), key, now()) {
+            return Err(InvalidToken.into());
         }
+        consumer.key = key.to_owned();
         return Ok(consumer);
     }
 
+    // The consumer with the given ID, the offset in front of its tokens. Lets admins
+    // manage consumers without knowing their key.
+    pub fn find(id: u64) -> Result<Self> {
+        let consumer = list_consumers()?.into_iter().find(|consumer| consumer.offset == id);
+        return consumer.ok_or(UnknownId { id }.into());
+    }
+
     pub fn delete(&self) -> Result<()> {
-        delete_consumer(&self)?;
+        delete_consumer(self)?;
         return Ok(());
     }
 
-    pub fn reroll(&mut self) -> Result<()> {
-        let new_key = reroll_consumer_key(&self)?;
+    // The replaced key keeps working for grace_ms, the new key expires at expires_at
+    pub fn reroll(&mut self, grace_ms: u64, expires_at: Option<u64>) -> Result<()> {
+        // Records from before tokens could expire have no room for an expiry
+        if self.expiry.is_none() && (grace_ms > 0 || expires_at.is_some()) {
+            return Err(InvalidInput { reason: "This token predates token expiry, create a new token to use expiry or a grace period.".to_string() }.into());
+        }
+        let (new_key, stored_key, expiry) = reroll_consumer_key(self, grace_ms, expires_at)?;
         self.key = new_key;
+        self.stored_key = stored_key;
+        self.expiry = expiry;
         return Ok(());
     }
 
+    // The consumer for the topic of a request, see TopicGrant::resolve. Consumers
+    // of several topics get the position record of the topic, which starts at the
+    // topic's first event.
+    pub fn for_topic(&self, topic: Option<&str>) -> Result<Consumer> {
+        let grant = TopicGrant::parse(&self.topic)?;
+        let topic = grant.resolve(topic)?;
+        if grant.single().is_some() {
+            return Ok(self.clone());
+        }
+        let first_log_file = Topic::hydrate(&topic)?.first_log_file;
+        let consumer = get_or_add_consumer_position(self.offset, &topic, first_log_file)?;
+        return Ok(consumer);
+    }
+
+    // Whether the consumer may read from the topic
+    pub fn grants(&self, topic: &str) -> bool {
+        return TopicGrant::parse(&self.topic).is_ok_and(|grant| grant.allows(topic));
+    }
+
     pub fn read(&mut self, bump: bool) -> Result<Event> {
-        let content = read(self)?;
-        if bump {
+        // Compaction can't move records between reading & committing
+        let lock = lock_topic(&self.topic, false)?;
+        self.reload_position()?;
+        let result = read(self);
+        if let (true, Ok(_)) = (bump, &result) {
             update_consumer_in_config(self)?;
         }
+        drop(lock);
+
+        let content = match result {
+            Ok(content) => content,
+            Err(e) => {
+                // Auto-committing consumers can't skip a corrupt record on their own
+                if bump {
+                    if let Some(eid) = e.downcast_ref::<CorruptRecord>().and_then(|c| c.skip_eid.clone()) {
+                        self.bump(&eid, false)?;
+                    }
+                }
+                return Err(e);
+            }
+        };
         return Ok(content);
     }
 
-    pub fn bump(&mut self, event_id: &str) -> Result<()> {
-        let event = event_id.split_once("-").unwrap_or(("",""));
-        if event.0 == "" || event.1 == "" {
-            return Err(anyhow!("Invalid token format."));
+    // Commits the event, moving the consumer past it. Moving backwards requires force.
+    pub fn bump(&mut self, event_id: &str, force: bool) -> Result<()> {
+        let (log_offset, log_file) = parse_event_id(event_id, &self.topic)?;
+        let _lock = lock_topic(&self.topic, false)?;
+        self.reload_position()?;
+        let topic = Topic::hydrate(&self.topic)?;
+        let log_offset = resolve_position(&topic, log_file, log_offset)?;
+        if !force && (log_file, log_offset) < (self.log_file, self.log_offset) {
+            return Err(StaleEventId {
+                event_id: event_id.to_owned(),
+                committed: format_event_id(&self.topic, original_position(&self.topic, self.log_file, self.log_offset)?, self.log_file),
+            }.into());
         }
-        let offset:u64 = event.0.parse()?;
-        let log_file:u64 = event.1.parse()?;
-        self.log_offset = offset;
+        self.log_offset = log_offset;
         self.log_file = log_file;
         update_consumer_in_config(self)?;
         return Ok(());
     }
+
+    // Compaction moves the positions of the consumers in the log files it rewrites,
+    // so positions read before taking the topic lock can point into a rewritten file
+    fn reload_position(&mut self) -> Result<()> {
+        let stored = get_consumer(self.offset)?;
+        self.log_file = stored.log_file;
+        self.log_offset = stored.log_offset;
+        return Ok(());
+    }
+
+    pub fn seek(&mut self, target: &SeekTarget) -> Result<()> {
+        let _lock = lock_topic(&self.topic, false)?;
+        let topic = Topic::hydrate(&self.topic)?;
+        let (log_file, log_offset) = match target {
+            SeekTarget::Offset(offset) => find_offset(&topic, *offset)?,
+            SeekTarget::Timestamp(timestamp) => find_timestamp(&topic, *timestamp)?,
+            SeekTarget::Earliest => (topic.first_log_file, 0),
+            SeekTarget::Latest => (topic.curr_log_file, get_topic_file(&topic.name, &topic.curr_log_file)?.len()?),
+            SeekTarget::EventId(event_id) => {
+                let (log_offset, log_file) = parse_event_id(event_id, &topic.name)?;
+                (log_file, resolve_position(&topic, log_file, log_offset)?)
+            },
+        };
+        self.log_file = log_file;
+        self.log_offset = log_offset;
+        update_consumer_in_config(self)?;
+        return Ok(());
+    }
+
+    // Reads the event at a message offset without moving the consumer
+    pub fn read_offset(&self, offset: u64) -> Result<Event> {
+        let _lock = lock_topic(&self.topic, false)?;
+        let topic = Topic::hydrate(&self.topic)?;
+        let (log_file, position) = find_offset(&topic, offset)?;
+        let event = read_at(&self.topic, &log_file, position)?;
+        return Ok(event);
+    }
 }
 
 impl Display for Consumer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return write!(f, "{{ \"topic\": \"{}\", \"log_file\": {}, \"log_offset\": {}, \"offset\": {}, \"key\": \"{}\" }}", self.topic, self.log_file, self.log_offset, self.offset, self.assemble_token());
+        let key = if self.key.is_empty() { "null".to_string() } else { format!("\"{}\"", self.assemble_token()) };
+        let (expires_at, previous_key_expires_at) = expiry_json(self.expiry.as_ref());
+        return write!(f, "{{ \"topic\": \"{}\", \"log_file\": {}, \"log_offset\": {}, \"offset\": {}, \"key\": {}, \"expires_at\": {}, \"previous_key_expires_at\": {} }}", self.topic, self.log_file, self.log_offset, self.offset, key, expires_at, previous_key_expires_at);
     }
 }
 
@@ -95,3 +226,34 @@ impl Token for Consumer {
         return format!("{}-{}", self.offset, self.key);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::{storage::test_storage, topics::write};
+
+    #[test]
+    fn reads_from_the_position_compaction_moved_it_to() {
+        let _storage = test_storage();
+        let topic = Topic::new("consumed-compacted".to_string()).unwrap();
+        topic.configure("segment_bytes", "1000").unwrap();
+        for event in 0..40 {
+            let key = format!("key {}", event % 4);
+            write(&topic.name, &Event::new(format!("event {}", event).into_bytes(), Some(key), Vec::new()), false).unwrap();
+        }
+        let token = Consumer::new(topic.name.clone(), None).unwrap().assemble_token();
+        let mut consumer = Consumer::hydrate(&token).unwrap();
+        for _ in 0..5 {
+            consumer.read(true).unwrap();
+        }
+
+        // Hydrated before compaction moves the consumer
+        let mut stale = Consumer::hydrate(&token).unwrap();
+        let compaction = Topic::hydrate(&topic.name).unwrap().compact().unwrap();
+        assert!(compaction.dropped > 0);
+
+        let expected = Consumer::hydrate(&token).unwrap().read(false).unwrap();
+        let event = stale.read(false).unwrap();
+        assert_eq!((event.eid, event.content), (expected.eid, expected.content));
+    }
+}
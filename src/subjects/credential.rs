@@ -0,0 +1,162 @@
+#![allow(unused)]
+
+use std::{fmt::Display, str::FromStr};
+use anyhow::{Result, anyhow};
+use crate::configs::{errors::{InvalidToken, InvalidInput, UnknownTopic, UnknownId}, topics::topic_exists, credentials::{add_credential_to_config, get_credential, delete_credential, reroll_credential_key, credentials_exists, list_credentials}};
+use super::keys::{generate_key, verify_key, KEY_LENGTH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    // manages everything, including topics & credentials
+    Admin,
+    // manages the producers, consumers & settings of one topic
+    Owner,
+    // reads everything, without seeing other tokens
+    Auditor,
+}
+
+impl Role {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Role::Admin => return 0,
+            Role::Owner => return 1,
+            Role::Auditor => return 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => return Some(Role::Admin),
+            1 => return Some(Role::Owner),
+            2 => return Some(Role::Auditor),
+            _ => return None,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "admin" => return Ok(Role::Admin),
+            "owner" => return Ok(Role::Owner),
+            "auditor" => return Ok(Role::Auditor),
+            _ => return Err(anyhow!("Invalid role. Valid roles are admin, owner and auditor.")),
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Admin => return write!(f, "admin"),
+            Role::Owner => return write!(f, "owner"),
+            Role::Auditor => return write!(f, "auditor"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Credentials authorize the admin API, producer & consumer tokens only reach
+// their own topic's events
+pub struct Credential {
+    pub offset: u64,
+    // Only known right after creating, hydrating or rerolling, the registry stores a hash
+    pub key: String,
+    pub stored_key: [u8; KEY_LENGTH],
+    pub role: Role,
+    // Topic of an owner
+    pub topic: Option<String>,
+}
+
+impl Credential {
+    pub fn new(role: Role, topic: Option<String>) -> Result<Self> {
+        let topic = topic.map(|topic| topic.to_lowercase());
+        match (role, &topic) {
+            (Role::Owner, None) => return Err(InvalidInput { reason: "Owners need a topic.".to_string() }.into()),
+            (Role::Owner, Some(topic)) if !topic_exists(topic) => return Err(UnknownTopic { topic: topic.to_owned() }.into()),
+            (Role::Admin | Role::Auditor, Some(_)) => return Err(InvalidInput { reason: format!("The {} role doesn't take a topic.", role) }.into()),
+            _ => {}
+        }
+        let mut credential = Credential {
+            offset: 0,
+            key: generate_key(),
+            stored_key: [0u8; KEY_LENGTH],
+            role,
+            topic,
+        };
+        add_credential_to_config(&mut credential)?;
+        return Ok(credential);
+    }
+
+    pub fn hydrate(token: &str) -> Result<Self> {
+        let (offset, key) = token.split_once("-").unwrap_or(("", ""));
+        if offset.is_empty() || key.is_empty() {
+            return Err(InvalidInput { reason: "Invalid token format.".to_string() }.into());
+        }
+        let offset:u64 = offset.parse().map_err(|_| InvalidInput { reason: "Invalid token format.".to_string() })?;
+        // Offsets past the end of the registry, or without a registry, belong to no one
+        let mut credential = get_credential(offset).map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if matches!(io.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::NotFound) => InvalidToken.into(),
+            _ => e,
+        })?;
+        if !verify_key(&credential.stored_key, key) {
+            return Err(InvalidToken.into());
+        }
+        credential.key = key.to_owned();
+        return Ok(credential);
+    }
+
+    // The credential with the given ID, the offset in front of its tokens
+    pub fn find(id: u64) -> Result<Self> {
+        let credential = list_credentials()?.into_iter().find(|credential| credential.offset == id);
+        return credential.ok_or(UnknownId { id }.into());
+    }
+
+    // Creates the first admin credential when there is no credential registry yet
+    pub fn bootstrap() -> Result<Option<Self>> {
+        if credentials_exists() {
+            return Ok(None);
+        }
+        return Ok(Some(Credential::new(Role::Admin, None)?));
+    }
+
+    pub fn delete(&self) -> Result<()> {
+        delete_credential(self)?;
+        return Ok(());
+    }
+
+    pub fn reroll(&mut self) -> Result<()> {
+        self.key = reroll_credential_key(self)?;
+        return Ok(());
+    }
+
+    // Whether the credential may read or change a topic & its producers and consumers.
+    // Without a topic, the access is to the whole server: listing, creating & deleting
+    // topics and managing credentials.
+    pub fn allows(&self, access: Access, topic: Option<&str>) -> bool {
+        match self.role {
+            Role::Admin => return true,
+            Role::Owner => return topic.is_some() && topic == self.topic.as_deref(),
+            Role::Auditor => return access == Access::Read,
+        }
+    }
+
+    pub fn token(&self) -> String {
+        return format!("{}-{}", self.offset, self.key);
+    }
+}
+
+impl Display for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let topic = self.topic.as_ref().map(|topic| format!("\"{}\"", topic)).unwrap_or("null".to_string());
+        let key = if self.key.is_empty() { "null".to_string() } else { format!("\"{}\"", self.token()) };
+        return write!(f, "{{ \"role\": \"{}\", \"topic\": {}, \"offset\": {}, \"key\": {} }}", self.role, topic, self.offset, key);
+    }
+}
@@ -1,12 +1,171 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::{SystemTime, UNIX_EPOCH}};
+use anyhow::{Result, anyhow};
+use crate::configs::{errors::InvalidEventId, topics::is_legacy_log_file};
 
 pub struct Event {
     pub eid: String,
+    // Milliseconds since the Unix epoch, assigned when the event is appended
+    pub timestamp: u64,
+    pub key: Option<String>,
+    pub headers: Vec<(String, String)>,
     pub content: Vec<u8>,
 }
 
+impl Event {
+    // An empty key is no key, compaction would otherwise keep one event for all of them
+    pub fn new(content: Vec<u8>, key: Option<String>, headers: Vec<(String, String)>) -> Self {
+        return Event {
+            eid: String::new(),
+            timestamp: now(),
+            key: key.filter(|key| !key.is_empty()),
+            headers,
+            content,
+        };
+    }
+}
+
+pub fn now() -> u64 {
+    return SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+}
+
+// Event IDs are formatted as <log offset>-<log file>-<topic tag>, the tag ties
+// the ID to the topic it was read from
+pub fn format_event_id(topic: &str, log_offset: u64, log_file: u64) -> String {
+    return format!("{}-{}-{}", log_offset, log_file, topic_tag(topic));
+}
+
+fn topic_tag(topic: &str) -> String {
+    return format!("{:08x}", crc32fast::hash(topic.as_bytes()));
+}
+
+// Returns the log offset & log file of an event ID read from the topic. Event IDs
+// handed out before the tag existed are <log offset>-<log file>, they are only
+// accepted for the headerless log files written back then.
+pub fn parse_event_id(event_id: &str, topic: &str) -> Result<(u64, u64)> {
+    let invalid = |reason: String| InvalidEventId { reason };
+    let parts = event_id.split('-').collect::<Vec<&str>>();
+    let (log_offset, log_file, tag) = match parts[..] {
+        [log_offset, log_file, tag] => (log_offset, log_file, Some(tag)),
+        [log_offset, log_file] => (log_offset, log_file, None),
+        _ => return Err(invalid(format!("{} is not formatted as <log offset>-<log file>-<topic tag>.", event_id)).into()),
+    };
+    let log_offset:u64 = log_offset.parse().map_err(|_| invalid(format!("{} has an invalid log offset.", event_id)))?;
+    let log_file:u64 = log_file.parse().map_err(|_| invalid(format!("{} has an invalid log file.", event_id)))?;
+    match tag {
+        Some(tag) if tag != topic_tag(topic) => return Err(invalid(format!("{} does not belong to topic {}.", event_id, topic)).into()),
+        None if !is_legacy_log_file(topic, &log_file)? => return Err(invalid(format!("{} has no topic tag.", event_id)).into()),
+        _ => {}
+    }
+    return Ok((log_offset, log_file));
+}
+
+// Accepts milliseconds since the Unix epoch or an RFC 3339 date time
+// such as 2023-03-16T10:42:00Z or 2023-03-16T10:42:00.250-05:00
+pub fn parse_timestamp(value: &str) -> Result<u64> {
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    let invalid = || anyhow!("Invalid timestamp {}. Use milliseconds since the Unix epoch or RFC 3339.", value);
+    if value.len() < 20 || !value.is_char_boundary(19) {
+        return Err(invalid());
+    }
+
+    let (date_time, zone) = value.split_at(19);
+    let number = |from: usize, to: usize| date_time.get(from..to).and_then(|n| n.parse::<i64>().ok()).ok_or_else(invalid);
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+
+    // Optional fraction of a second, then Z or an offset from UTC
+    let (fraction, zone) = match zone.strip_prefix('.') {
+        Some(rest) => rest.split_at(rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len())),
+        None => ("", zone),
+    };
+    let millis = format!("{:0<3}", fraction).get(0..3).and_then(|n| n.parse::<i64>().ok()).ok_or_else(invalid)?;
+    let offset_minutes = match zone {
+        "Z" | "z" => 0,
+        _ if zone.len() == 6 => {
+            let hours = zone.get(1..3).and_then(|n| n.parse::<i64>().ok()).ok_or_else(invalid)?;
+            let minutes = zone.get(4..6).and_then(|n| n.parse::<i64>().ok()).ok_or_else(invalid)?;
+            match &zone[0..1] {
+                "+" => hours * 60 + minutes,
+                "-" => -(hours * 60 + minutes),
+                _ => return Err(invalid()),
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    // Days since the Unix epoch for the civil date (proleptic Gregorian calendar)
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset_minutes * 60;
+    if seconds < 0 {
+        return Err(invalid());
+    }
+    return Ok((seconds * 1000 + millis) as u64);
+}
+
 impl Display for Event {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return write!(f, "{{ \"eid\": \"{}\", \"content\": \"{}\" }}", self.eid, self.content.len());
+        return write!(f, "{{ \"eid\": \"{}\", \"timestamp\": {}, \"key\": \"{}\", \"content\": \"{}\" }}", self.eid, self.timestamp, self.key.as_deref().unwrap_or(""), self.content.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::storage::{test_storage, storage};
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1678981320250").unwrap(), 1678981320250);
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_timestamp("2023-03-16T10:42:00.250-05:00").unwrap(), 1678981320250);
+        assert_eq!(parse_timestamp("2023-03-16T15:42:00.25Z").unwrap(), 1678981320250);
+        assert_eq!(parse_timestamp("2000-02-29T23:59:59+00:00").unwrap(), 951868799000);
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for value in ["yesterday", "2023-03-16", "2023-13-01T00:00:00Z", "2023-03-16T24:00:00Z", "2023-03-16T10:42:00", "2023-03-16T10:42:00+0500", "1969-12-31T23:59:59Z"] {
+            assert!(parse_timestamp(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_event_ids() {
+        let event_id = format_event_id("orders", 120, 3);
+        assert_eq!(parse_event_id(&event_id, "orders").unwrap(), (120, 3));
+        assert!(parse_event_id(&event_id, "payments").is_err());
+
+        // Event IDs from before the topic tag, which only headerless log files have
+        let _storage = test_storage();
+        storage().create_dir_all("logs/untagged").unwrap();
+        storage().write("logs/untagged/0", &[0u8; 16]).unwrap();
+        storage().write("logs/untagged/1", b"SFLG\0\0\0\x03").unwrap();
+        assert_eq!(parse_event_id("16-0", "untagged").unwrap(), (16, 0));
+        assert!(parse_event_id("8-1", "untagged").is_err());
+        assert!(parse_event_id("8-2", "untagged").is_err());
+
+        for event_id in ["120", "a-3", "120-b", "1-2-3-4"] {
+            assert!(parse_event_id(event_id, "orders").is_err(), "{}", event_id);
+        }
+    }
+
+    #[test]
+    fn empty_keys_are_no_keys() {
+        assert_eq!(Event::new(Vec::new(), Some(String::new()), Vec::new()).key, None);
+        assert_eq!(Event::new(Vec::new(), Some("key".to_string()), Vec::new()).key, Some("key".to_string()));
     }
 }
@@ -1,6 +1,221 @@
 use uuid::Uuid;
+use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+
+// Registries store a 16 byte salt followed by the first 20 bytes of
+// SHA-256(salt + key). That's the 36 bytes a plain UUID key took before keys
+// were hashed, so records keep their offsets & tokens keep working.
+pub const KEY_LENGTH: usize = 36;
+const SALT_LENGTH: usize = 16;
 
 pub fn generate_key() -> String {
     let uuid = Uuid::new_v4();
     return uuid.to_string();
 }
+
+pub fn hash_key(key: &str) -> [u8; KEY_LENGTH] {
+    let salt = Uuid::new_v4().into_bytes();
+    let mut stored_key = [0u8; KEY_LENGTH];
+    stored_key[..SALT_LENGTH].copy_from_slice(&salt);
+    stored_key[SALT_LENGTH..].copy_from_slice(&digest(&salt, key)[..KEY_LENGTH - SALT_LENGTH]);
+    return stored_key;
+}
+
+fn digest(salt: &[u8], key: &str) -> [u8; 32] {
+    return Sha256::new()
+                .chain_update(salt)
+                .chain_update(key.as_bytes())
+                .finalize()
+                .into();
+}
+
+// Deleted records have their key overwritten with null bytes
+pub fn is_deleted_key(stored_key: &[u8; KEY_LENGTH]) -> bool {
+    return stored_key.iter().all(|byte| *byte == 0);
+}
+
+// Keys written before keys were hashed, until `sailfish-cli migrate` hashes them
+pub fn is_plain_key(stored_key: &[u8; KEY_LENGTH]) -> bool {
+    return Uuid::try_parse_ascii(stored_key).is_ok();
+}
+
+pub fn verify_key(stored_key: &[u8; KEY_LENGTH], key: &str) -> bool {
+    if is_deleted_key(stored_key) {
+        return false;
+    }
+    if is_plain_key(stored_key) {
+        return stored_key.ct_eq(key.as_bytes()).into();
+    }
+    let digest = digest(&stored_key[..SALT_LENGTH], key);
+    return stored_key[SALT_LENGTH..].ct_eq(&digest[..KEY_LENGTH - SALT_LENGTH]).into();
+}
+
+// Records created since tokens can expire end with a key expiry, flagged in their
+// topic length. Older records have none, their keys never expire.
+pub const KEY_EXPIRY_FLAG: u64 = 1 << 63;
+pub const KEY_EXPIRY_LENGTH: u64 = 52;
+
+// Timestamps are milliseconds since the Unix epoch, 0 means never. After a reroll
+// with a grace period the previous key keeps working until previous_expires_at.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyExpiry {
+    pub expires_at: u64,
+    pub previous_key: [u8; KEY_LENGTH],
+    pub previous_expires_at: u64,
+}
+
+impl KeyExpiry {
+    pub fn new(expires_at: Option<u64>) -> Self {
+        return KeyExpiry {
+            expires_at: expires_at.unwrap_or(0),
+            previous_key: [0u8; KEY_LENGTH],
+            previous_expires_at: 0,
+        };
+    }
+
+    pub fn to_bytes(self) -> [u8; KEY_EXPIRY_LENGTH as usize] {
+        let mut bytes = [0u8; KEY_EXPIRY_LENGTH as usize];
+        bytes[..8].copy_from_slice(&self.expires_at.to_be_bytes());
+        bytes[8..44].copy_from_slice(&self.previous_key);
+        bytes[44..].copy_from_slice(&self.previous_expires_at.to_be_bytes());
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8; KEY_EXPIRY_LENGTH as usize]) -> Self {
+        let mut previous_key = [0u8; KEY_LENGTH];
+        previous_key.copy_from_slice(&bytes[8..44]);
+        return KeyExpiry {
+            expires_at: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            previous_key,
+            previous_expires_at: u64::from_be_bytes(bytes[44..].try_into().unwrap()),
+        };
+    }
+
+    // The expiry of the rerolled key, the key it replaces stays valid for the grace period
+    pub fn reroll(&self, replaced_key: &[u8; KEY_LENGTH], grace_ms: u64, expires_at: Option<u64>, now: u64) -> Self {
+        let mut previous_expires_at = now.saturating_add(grace_ms);
+        if self.expires_at != 0 {
+            previous_expires_at = previous_expires_at.min(self.expires_at);
+        }
+        return KeyExpiry {
+            expires_at: expires_at.unwrap_or(0),
+            previous_key: if grace_ms > 0 { *replaced_key } else { [0u8; KEY_LENGTH] },
+            previous_expires_at: if grace_ms > 0 { previous_expires_at } else { 0 },
+        };
+    }
+}
+
+// Verifies the key against the record's current key & expiry, or its previous key
+// during the grace period. Deleted records & position records take neither.
+pub fn verify_key_at(stored_key: &[u8; KEY_LENGTH], expiry: Option<&KeyExpiry>, key: &str, now: u64) -> bool {
+    if is_deleted_key(stored_key) || position_owner(stored_key).is_some() {
+        return false;
+    }
+    let expiry = match expiry {
+        Some(expiry) => expiry,
+        None => return verify_key(stored_key, key),
+    };
+    let expired = expiry.expires_at != 0 && now >= expiry.expires_at;
+    if !expired && verify_key(stored_key, key) {
+        return true;
+    }
+    return now < expiry.previous_expires_at && verify_key(&expiry.previous_key, key);
+}
+
+// The expires_at & previous_key_expires_at JSON values of a token, null for never
+pub fn expiry_json(expiry: Option<&KeyExpiry>) -> (String, String) {
+    let timestamp = |timestamp: u64| if timestamp == 0 { "null".to_string() } else { timestamp.to_string() };
+    match expiry {
+        Some(expiry) => return (timestamp(expiry.expires_at), timestamp(expiry.previous_expires_at)),
+        None => return ("null".to_string(), "null".to_string()),
+    }
+}
+
+// Position records of consumers with several topics hold the consumer's offset in
+// place of a key, behind a marker of 28 0xff bytes. They can't be used as tokens.
+const POSITION_MARKER_LENGTH: usize = 28;
+
+pub fn position_key(consumer_offset: u64) -> [u8; KEY_LENGTH] {
+    let mut stored_key = [0xffu8; KEY_LENGTH];
+    stored_key[POSITION_MARKER_LENGTH..].copy_from_slice(&consumer_offset.to_be_bytes());
+    return stored_key;
+}
+
+// The offset of the consumer a position record belongs to
+pub fn position_owner(stored_key: &[u8; KEY_LENGTH]) -> Option<u64> {
+    if !stored_key[..POSITION_MARKER_LENGTH].iter().all(|byte| *byte == 0xff) {
+        return None;
+    }
+    return Some(u64::from_be_bytes(stored_key[POSITION_MARKER_LENGTH..].try_into().unwrap()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_hashed_keys() {
+        let key = generate_key();
+        let stored_key = hash_key(&key);
+        assert!(verify_key(&stored_key, &key));
+        assert!(!verify_key(&stored_key, &generate_key()));
+        assert!(!is_plain_key(&stored_key));
+
+        // Every hash has its own salt
+        assert_ne!(hash_key(&key), stored_key);
+    }
+
+    #[test]
+    fn verifies_plain_keys() {
+        let key = generate_key();
+        let mut stored_key = [0u8; KEY_LENGTH];
+        stored_key.copy_from_slice(key.as_bytes());
+        assert!(is_plain_key(&stored_key));
+        assert!(verify_key(&stored_key, &key));
+        assert!(!verify_key(&stored_key, &generate_key()));
+    }
+
+    #[test]
+    fn rejects_deleted_and_position_records() {
+        assert!(!verify_key(&[0u8; KEY_LENGTH], ""));
+        let position = position_key(45);
+        assert_eq!(position_owner(&position), Some(45));
+        assert_eq!(position_owner(&hash_key("key")), None);
+        assert!(!verify_key_at(&position, None, "", 0));
+    }
+
+    #[test]
+    fn expires_keys() {
+        let key = generate_key();
+        let stored_key = hash_key(&key);
+        let expiry = KeyExpiry::new(Some(1000));
+        assert!(verify_key_at(&stored_key, Some(&expiry), &key, 999));
+        assert!(!verify_key_at(&stored_key, Some(&expiry), &key, 1000));
+        assert!(verify_key_at(&stored_key, Some(&KeyExpiry::new(None)), &key, u64::MAX));
+        assert!(verify_key_at(&stored_key, None, &key, u64::MAX));
+
+        let expiry = KeyExpiry::from_bytes(&expiry.to_bytes());
+        assert_eq!(expiry.expires_at, 1000);
+    }
+
+    #[test]
+    fn keeps_rerolled_keys_for_the_grace_period() {
+        let old_key = generate_key();
+        let old_stored_key = hash_key(&old_key);
+        let new_key = generate_key();
+        let new_stored_key = hash_key(&new_key);
+
+        let expiry = KeyExpiry::new(None).reroll(&old_stored_key, 500, None, 1000);
+        assert!(verify_key_at(&new_stored_key, Some(&expiry), &new_key, 1000));
+        assert!(verify_key_at(&new_stored_key, Some(&expiry), &old_key, 1499));
+        assert!(!verify_key_at(&new_stored_key, Some(&expiry), &old_key, 1500));
+
+        // The grace period never outlives the replaced key
+        let expiry = KeyExpiry::new(Some(1200)).reroll(&old_stored_key, 500, None, 1000);
+        assert_eq!(expiry.previous_expires_at, 1200);
+
+        // Without a grace period the replaced key stops working right away
+        let expiry = KeyExpiry::new(None).reroll(&old_stored_key, 0, None, 1000);
+        assert!(!verify_key_at(&new_stored_key, Some(&expiry), &old_key, 1000));
+    }
+}
@@ -3,3 +3,5 @@ pub mod keys;
 pub mod topic;
 pub mod consumer;
 pub mod event;
+pub mod settings;
+pub mod credential;
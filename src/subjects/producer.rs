@@ -2,26 +2,38 @@
 
 use std::fmt::Display;
 use anyhow::{Result, anyhow};
-use crate::configs::{topics::{topic_exists, write}, producers::{add_producer_to_config, get_producer, delete_producer, reroll_producer_key, producers_exists, create_producers_file}};
-use super::{keys::generate_key, topic::Topic};
+use crate::configs::{errors::{UnknownTopic, InvalidToken, InvalidInput, TopicForbidden, UnknownId}, topics::{topic_exists, write}, producers::{add_producer_to_config, get_producer, delete_producer, reroll_producer_key, producers_exists, create_producers_file, list_producers}};
+use super::{keys::{generate_key, verify_key_at, expiry_json, KeyExpiry, KEY_LENGTH}, topic::{Topic, TopicGrant}, event::{Event, now}};
 
+#[derive(Clone)]
 pub struct Producer {
+    // A TopicGrant, which is just the topic for single topic producers
     pub topic: String,
     pub offset: u64,
+    // Only known right after creating, hydrating or rerolling, registries store a hash
     pub key: String,
+    pub stored_key: [u8; KEY_LENGTH],
+    // None for tokens created before tokens could expire
+    pub expiry: Option<KeyExpiry>,
 }
 
 impl Producer {
-    pub fn new(topic: String) -> Result<Self> {
-        if !topic_exists(&topic){
-            return Err(anyhow!("Topic {} has not been created yet.", topic));
+    // The topic can also grant several topics, see TopicGrant. Tokens expire at the
+    // given time, in milliseconds since the Unix epoch.
+    pub fn new(topic: String, expires_at: Option<u64>) -> Result<Self> {
+        let grant = TopicGrant::parse(&topic)?;
+        for topic in grant.topics() {
+            if !topic_exists(topic){
+                return Err(UnknownTopic { topic: topic.to_owned() }.into());
+            }
         }
-        let topic = Topic::hydrate(&topic)?;
         let key = generate_key();
         let mut producer = Producer{
-            topic: topic.name,
+            topic: grant.to_string(),
             offset: 0,
             key,
+            stored_key: [0u8; KEY_LENGTH],
+            expiry: Some(KeyExpiry::new(expires_at)),
         };
         if !producers_exists() {
             create_producers_file();
@@ -30,39 +42,75 @@ impl Producer {
         return Ok(producer);
     }
 
-    pub fn hydrate(token: &String) -> Result<Self> {
+    pub fn hydrate(token: &str) -> Result<Self> {
         let offset = token.split_once("-").unwrap_or(("", ""));
-        if offset.0 == "" || offset.1 == "" {
-            return Err(anyhow!("Invalid token format."));
+        if offset.0.is_empty() || offset.1.is_empty() {
+            return Err(InvalidInput { reason: "Invalid token format.".to_string() }.into());
         }
-        let offset:u64 = offset.0.parse()?;
-        let producer = get_producer(offset)?;
-        if &producer.key != token.split_once("-").unwrap_or(("", "")).1 {
-            return Err(anyhow!("Unauthorized."));
+        let offset:u64 = offset.0.parse().map_err(|_| InvalidInput { reason: "Invalid token format.".to_string() })?;
+        // Offsets past the end of the registry, or without a registry, belong to no one
+        let mut producer = get_producer(offset).map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if matches!(io.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::NotFound) => InvalidToken.into(),
+            _ => e,
+        })?;
+        let key = token.split_once("-").unwrap_or(("", "")).1;
+        if !verify_key_at(&producer.stored_key, producer.expiry.as_ref(), key, now()) {
+            return Err(InvalidToken.into());
         }
+        producer.key = key.to_owned();
         return Ok(producer);
     }
 
+    // The producer with the given ID, the offset in front of its tokens. Lets admins
+    // manage producers without knowing their key.
+    pub fn find(id: u64) -> Result<Self> {
+        let producer = list_producers()?.into_iter().find(|producer| producer.offset == id);
+        return producer.ok_or(UnknownId { id }.into());
+    }
+
     pub fn delete(&self) -> Result<()> {
-        delete_producer(&self)?;
+        delete_producer(self)?;
         return Ok(());
     }
 
-    pub fn reroll(&mut self) -> Result<()> {
-        let new_key = reroll_producer_key(&self)?;
+    // The replaced key keeps working for grace_ms, the new key expires at expires_at
+    pub fn reroll(&mut self, grace_ms: u64, expires_at: Option<u64>) -> Result<()> {
+        // Records from before tokens could expire have no room for an expiry
+        if self.expiry.is_none() && (grace_ms > 0 || expires_at.is_some()) {
+            return Err(InvalidInput { reason: "This token predates token expiry, create a new token to use expiry or a grace period.".to_string() }.into());
+        }
+        let (new_key, stored_key, expiry) = reroll_producer_key(self, grace_ms, expires_at)?;
         self.key = new_key;
+        self.stored_key = stored_key;
+        self.expiry = expiry;
         return Ok(());
     }
 
-    pub fn write(&self, content: &[u8]) -> Result<()> {
-        write(&self.topic, content)?;
-        return Ok(());
+    // The producer for the topic of a request, see TopicGrant::resolve
+    pub fn for_topic(&self, topic: Option<&str>) -> Result<Producer> {
+        let topic = TopicGrant::parse(&self.topic)?.resolve(topic)?;
+        let mut producer = self.clone();
+        producer.topic = topic;
+        return Ok(producer);
+    }
+
+    // Whether the producer may write to the topic
+    pub fn grants(&self, topic: &str) -> bool {
+        return TopicGrant::parse(&self.topic).is_ok_and(|grant| grant.allows(topic));
+    }
+
+    // Returns the log file the event was appended to
+    pub fn write(&self, event: &Event, sync: bool) -> Result<u64> {
+        let log_file = write(&self.topic, event, sync)?;
+        return Ok(log_file);
     }
 }
 
 impl Display for Producer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        return write!(f, "{{ \"topic\": \"{}\", \"offset\": {}, \"key\": \"{}\"}}", self.topic, self.offset, self.assemble_token());
+        let key = if self.key.is_empty() { "null".to_string() } else { format!("\"{}\"", self.assemble_token()) };
+        let (expires_at, previous_key_expires_at) = expiry_json(self.expiry.as_ref());
+        return write!(f, "{{ \"topic\": \"{}\", \"offset\": {}, \"key\": {}, \"expires_at\": {}, \"previous_key_expires_at\": {}}}", self.topic, self.offset, key, expires_at, previous_key_expires_at);
     }
 }
 
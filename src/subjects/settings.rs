@@ -0,0 +1,295 @@
+use std::{fmt::Display, str::FromStr};
+use anyhow::{Result, anyhow};
+use crate::configs::errors::MessageTooLarge;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    // fsync before every write is acknowledged
+    Always,
+    // fsync batches of writes on an interval or byte threshold (group commit)
+    Interval,
+    // leave flushing to the operating system
+    None,
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "always" => return Ok(Durability::Always),
+            "interval" => return Ok(Durability::Interval),
+            "none" => return Ok(Durability::None),
+            _ => return Err(anyhow!("Invalid durability mode. Valid modes are always, interval and none.")),
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Durability::Always => return write!(f, "always"),
+            Durability::Interval => return write!(f, "interval"),
+            Durability::None => return write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanupPolicy {
+    // delete whole log files by age, size or once every consumer has read them
+    Delete,
+    // keep only the newest event per key in closed log files
+    Compact,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "delete" => return Ok(CleanupPolicy::Delete),
+            "compact" => return Ok(CleanupPolicy::Compact),
+            _ => return Err(anyhow!("Invalid cleanup policy. Valid policies are delete and compact.")),
+        }
+    }
+}
+
+impl Display for CleanupPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CleanupPolicy::Delete => return write!(f, "delete"),
+            CleanupPolicy::Compact => return write!(f, "compact"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => return Ok(Compression::None),
+            "zstd" => return Ok(Compression::Zstd),
+            "lz4" => return Ok(Compression::Lz4),
+            _ => return Err(anyhow!("Invalid compression. Valid compressions are none, zstd and lz4.")),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => return write!(f, "none"),
+            Compression::Zstd => return write!(f, "zstd"),
+            Compression::Lz4 => return write!(f, "lz4"),
+        }
+    }
+}
+
+// Log files roll over once they reach 1GB unless the topic says otherwise
+pub const DEFAULT_SEGMENT_BYTES:u64 = 1000000000;
+
+// Compaction keeps tombstones for a day so consumers get to see the delete
+pub const DEFAULT_TOMBSTONE_RETENTION_MS:u64 = 86400000;
+
+// Per-topic overrides, unset values fall back to the server defaults
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub durability: Option<Durability>,
+    // Maximum size of a log file in bytes
+    pub segment_bytes: Option<u64>,
+    // Maximum age of a log file in milliseconds, measured from its first event
+    pub segment_ms: Option<u64>,
+    // Log files whose newest event is older than this many milliseconds are deleted
+    pub retention_ms: Option<u64>,
+    // Oldest log files are deleted while the topic is larger than this many bytes
+    pub retention_bytes: Option<u64>,
+    // Log files every consumer has read past are only deleted once their newest
+    // event is older than this many milliseconds
+    pub min_retention_ms: Option<u64>,
+    pub cleanup_policy: Option<CleanupPolicy>,
+    // How long compaction keeps the newest event of a key when it has no content
+    pub tombstone_retention_ms: Option<u64>,
+    // Codec for new events, existing events keep the codec they were written with
+    pub compression: Option<Compression>,
+    // Largest event content accepted, capped by the server's maximum
+    pub max_message_bytes: Option<u64>,
+    // Writes & bytes per second across all producers of the topic
+    pub requests_per_second: Option<u64>,
+    pub bytes_per_second: Option<u64>,
+    // Writes & bytes per second of each producer, replacing the server's producer limits
+    pub producer_requests_per_second: Option<u64>,
+    pub producer_bytes_per_second: Option<u64>,
+}
+
+fn parse_number(key: &str, value: &str) -> Result<Option<u64>> {
+    if value == "default" {
+        return Ok(None);
+    }
+    let number = value.parse::<u64>().map_err(|_| anyhow!("Invalid {} value {}, expected a number.", key, value))?;
+    return Ok(Some(number));
+}
+
+fn parse_positive_number(key: &str, value: &str) -> Result<Option<u64>> {
+    let number = parse_number(key, value).map_err(|_| anyhow!("Invalid {} value {}, expected a positive number.", key, value))?;
+    if number == Some(0) {
+        return Err(anyhow!("Invalid {} value {}, expected a positive number.", key, value));
+    }
+    return Ok(number);
+}
+
+fn format_number(number: Option<u64>) -> String {
+    return number.map(|n| n.to_string()).unwrap_or("null".to_string());
+}
+
+impl Settings {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "durability" => {
+                self.durability = match value {
+                    "default" => None,
+                    _ => Some(value.parse()?),
+                };
+            }
+            "segment_bytes" => self.segment_bytes = parse_positive_number(key, value)?,
+            "segment_ms" => self.segment_ms = parse_positive_number(key, value)?,
+            "retention_ms" => self.retention_ms = parse_positive_number(key, value)?,
+            "retention_bytes" => self.retention_bytes = parse_positive_number(key, value)?,
+            "min_retention_ms" => self.min_retention_ms = parse_positive_number(key, value)?,
+            "cleanup_policy" => {
+                self.cleanup_policy = match value {
+                    "default" => None,
+                    _ => Some(value.parse()?),
+                };
+            }
+            // 0 drops tombstones at the next compaction
+            "tombstone_retention_ms" => self.tombstone_retention_ms = parse_number(key, value)?,
+            "compression" => {
+                self.compression = match value {
+                    "default" => None,
+                    _ => Some(value.parse()?),
+                };
+            }
+            "max_message_bytes" => self.max_message_bytes = parse_positive_number(key, value)?,
+            "requests_per_second" => self.requests_per_second = parse_positive_number(key, value)?,
+            "bytes_per_second" => self.bytes_per_second = parse_positive_number(key, value)?,
+            "producer_requests_per_second" => self.producer_requests_per_second = parse_positive_number(key, value)?,
+            "producer_bytes_per_second" => self.producer_bytes_per_second = parse_positive_number(key, value)?,
+            _ => return Err(anyhow!("Unknown setting {}.", key)),
+        }
+        return Ok(());
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut settings = Settings::default();
+        for line in contents.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                settings.set(key.trim(), value.trim())?;
+            }
+        }
+        return Ok(settings);
+    }
+
+    // key=value lines, as stored on disk
+    pub fn serialize(&self) -> String {
+        let mut contents = String::new();
+        if let Some(durability) = self.durability {
+            contents.push_str(&format!("durability={}\n", durability));
+        }
+        if let Some(segment_bytes) = self.segment_bytes {
+            contents.push_str(&format!("segment_bytes={}\n", segment_bytes));
+        }
+        if let Some(segment_ms) = self.segment_ms {
+            contents.push_str(&format!("segment_ms={}\n", segment_ms));
+        }
+        if let Some(retention_ms) = self.retention_ms {
+            contents.push_str(&format!("retention_ms={}\n", retention_ms));
+        }
+        if let Some(retention_bytes) = self.retention_bytes {
+            contents.push_str(&format!("retention_bytes={}\n", retention_bytes));
+        }
+        if let Some(min_retention_ms) = self.min_retention_ms {
+            contents.push_str(&format!("min_retention_ms={}\n", min_retention_ms));
+        }
+        if let Some(cleanup_policy) = self.cleanup_policy {
+            contents.push_str(&format!("cleanup_policy={}\n", cleanup_policy));
+        }
+        if let Some(tombstone_retention_ms) = self.tombstone_retention_ms {
+            contents.push_str(&format!("tombstone_retention_ms={}\n", tombstone_retention_ms));
+        }
+        if let Some(compression) = self.compression {
+            contents.push_str(&format!("compression={}\n", compression));
+        }
+        if let Some(max_message_bytes) = self.max_message_bytes {
+            contents.push_str(&format!("max_message_bytes={}\n", max_message_bytes));
+        }
+        if let Some(requests_per_second) = self.requests_per_second {
+            contents.push_str(&format!("requests_per_second={}\n", requests_per_second));
+        }
+        if let Some(bytes_per_second) = self.bytes_per_second {
+            contents.push_str(&format!("bytes_per_second={}\n", bytes_per_second));
+        }
+        if let Some(producer_requests_per_second) = self.producer_requests_per_second {
+            contents.push_str(&format!("producer_requests_per_second={}\n", producer_requests_per_second));
+        }
+        if let Some(producer_bytes_per_second) = self.producer_bytes_per_second {
+            contents.push_str(&format!("producer_bytes_per_second={}\n", producer_bytes_per_second));
+        }
+        return contents;
+    }
+
+    // The topic can lower the server's maximum message size but not raise it
+    pub fn check_message_size(&self, size: u64, server_max: u64) -> Result<()> {
+        let limit = self.max_message_bytes.map(|max| max.min(server_max)).unwrap_or(server_max);
+        if size > limit {
+            return Err(MessageTooLarge { size, limit }.into());
+        }
+        return Ok(());
+    }
+}
+
+impl Display for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let durability = self.durability.map(|d| d.to_string()).unwrap_or("default".to_string());
+        return write!(f, "{{ \"durability\": \"{}\", \"segment_bytes\": {}, \"segment_ms\": {}, \"retention_ms\": {}, \"retention_bytes\": {}, \"min_retention_ms\": {}, \"cleanup_policy\": \"{}\", \"tombstone_retention_ms\": {}, \"compression\": \"{}\", \"max_message_bytes\": {}, \"requests_per_second\": {}, \"bytes_per_second\": {}, \"producer_requests_per_second\": {}, \"producer_bytes_per_second\": {} }}",
+            durability,
+            self.segment_bytes.unwrap_or(DEFAULT_SEGMENT_BYTES),
+            format_number(self.segment_ms),
+            format_number(self.retention_ms),
+            format_number(self.retention_bytes),
+            format_number(self.min_retention_ms),
+            self.cleanup_policy.unwrap_or(CleanupPolicy::Delete),
+            self.tombstone_retention_ms.unwrap_or(DEFAULT_TOMBSTONE_RETENTION_MS),
+            self.compression.unwrap_or(Compression::None),
+            format_number(self.max_message_bytes),
+            format_number(self.requests_per_second),
+            format_number(self.bytes_per_second),
+            format_number(self.producer_requests_per_second),
+            format_number(self.producer_bytes_per_second));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_tombstone_retention_can_be_zero() {
+        let mut settings = Settings::default();
+        settings.set("tombstone_retention_ms", "0").unwrap();
+        assert_eq!(settings.tombstone_retention_ms, Some(0));
+        assert_eq!(Settings::parse(&settings.serialize()).unwrap().tombstone_retention_ms, Some(0));
+        for key in ["segment_bytes", "retention_ms", "max_message_bytes", "requests_per_second"] {
+            assert!(settings.set(key, "0").is_err(), "{}", key);
+        }
+        assert!(settings.set("tombstone_retention_ms", "-1").is_err());
+    }
+}
@@ -1,9 +1,17 @@
 #![allow(unused)]
 
 use std::fmt::Display;
-use crate::configs::{topics::{create_topic_dir, add_topic_to_config, topic_exists, get_topic_from_config, delete_topic, delete_topic_dir, update_topic_in_config, delete_old_logs}, consumers::get_oldest_active_log_file};
+use crate::configs::{topics::{add_topic_to_config, topic_exists, get_topic_from_config, delete_topic, delete_topic_dir, update_first_log_file_in_config, update_curr_log_file_in_config, delete_old_logs, get_topic_file, get_log_file_sizes, recover_topic_file, get_topic_settings, set_topic_settings, delete_topic_settings}, consumers::{get_oldest_active_log_file, consumers_exists}};
 use anyhow::{Result, anyhow};
+use super::settings::{Settings, DEFAULT_TOMBSTONE_RETENTION_MS};
+use crate::configs::compaction::{compact_topic, Compaction};
+use crate::configs::storage::lock_topic;
+use crate::configs::credentials::delete_topic_credentials;
+use crate::configs::errors::{UnknownTopic, TopicForbidden, InvalidInput};
+use crate::configs::indexes::{get_offset_range, get_event_count, get_last_timestamp};
+use super::event::now;
 
+#[derive(Clone)]
 pub struct Topic {
     pub name: String,
     pub first_log_file: u64,
@@ -15,9 +23,8 @@ impl Topic {
     pub fn new(name: String) -> Result<Self> {
         let name = name.to_lowercase();
         if !Topic::validate(&name) {
-            return Err(anyhow!("Invalid topic name."));
+            return Err(InvalidInput { reason: "Invalid topic name.".to_string() }.into());
         }
-        create_topic_dir(&name)?;
         let topic = Topic {
             name,
             first_log_file: 0,
@@ -29,8 +36,8 @@ impl Topic {
     }
 
     pub fn hydrate(name: &str) -> Result<Self, anyhow::Error> {
-        if !topic_exists(&name) {
-            return Err(anyhow!("Topic {} has not been created yet.", name));
+        if !Topic::loadable(name) || !topic_exists(name) {
+            return Err(UnknownTopic { topic: name.to_owned() }.into());
         }
         let mut topic = Topic {
             name: name.to_owned(),
@@ -43,22 +50,163 @@ impl Topic {
     }
 
     pub fn delete(&self) -> Result<()> {
-        delete_topic(&self)?;
+        let _lock = lock_topic(&self.name, true)?;
+        delete_topic(self)?;
         delete_topic_dir(&self.name)?;
+        delete_topic_settings(&self.name)?;
+        delete_topic_credentials(&self.name)?;
         return Ok(());
     }
 
     pub fn bump(&mut self) -> Result<()> {
         self.curr_log_file += 1;
-        update_topic_in_config(&self)?;
+        update_curr_log_file_in_config(self)?;
         return Ok(());
     }
 
-    pub fn cleanup(&mut self) -> Result<()> {
-        self.first_log_file = get_oldest_active_log_file(&self.name)?.unwrap_or(self.first_log_file);
-        update_topic_in_config(&self)?;
+    pub fn settings(&self) -> Result<Settings> {
+        return get_topic_settings(&self.name);
+    }
+
+    pub fn configure(&self, key: &str, value: &str) -> Result<Settings> {
+        let mut settings = get_topic_settings(&self.name)?;
+        settings.set(key, value).map_err(|e| InvalidInput { reason: e.to_string() })?;
+        set_topic_settings(&self.name, &settings)?;
+        return Ok(settings);
+    }
+
+    // Offset of the first retained message and the offset the next message will get
+    pub fn offsets(&self) -> Result<(u64, u64)> {
+        return get_offset_range(self);
+    }
+
+    pub fn message_count(&self) -> Result<u64> {
+        let mut count:u64 = 0;
+        for log_file in self.first_log_file..=self.curr_log_file {
+            count += get_event_count(&self.name, &log_file)?;
+        }
+        return Ok(count);
+    }
+
+    // Bytes the retained events take uncompressed & on disk
+    pub fn sizes(&self) -> Result<(u64, u64)> {
+        // Compaction & recovery reset the size indexes this fills in
+        let _lock = lock_topic(&self.name, false)?;
+        let mut raw_bytes:u64 = 0;
+        let mut disk_bytes:u64 = 0;
+        for log_file in self.first_log_file..=self.curr_log_file {
+            let (raw, disk) = get_log_file_sizes(&self.name, &log_file)?;
+            raw_bytes += raw;
+            disk_bytes += disk;
+        }
+        return Ok((raw_bytes, disk_bytes));
+    }
+
+    pub fn stats(&self) -> Result<TopicStats> {
+        let (first_offset, next_offset) = self.offsets()?;
+        let (raw_bytes, disk_bytes) = self.sizes()?;
+        return Ok(TopicStats {
+            topic: self.clone(),
+            messages: self.message_count()?,
+            first_offset,
+            next_offset,
+            raw_bytes,
+            disk_bytes,
+        });
+    }
+
+    pub fn recover(&self) -> Result<u64> {
+        let _lock = lock_topic(&self.name, true)?;
+        let dropped_bytes = recover_topic_file(self)?;
+        return Ok(dropped_bytes);
+    }
+
+    // Deletes the log files every consumer has read past, keeping the ones
+    // still inside the topic's minimum retention
+    pub fn cleanup(&mut self) -> Result<u64> {
+        let settings = self.settings()?;
+        let first_log_file = self.consumed_log_file(settings.min_retention_ms)?;
+        return self.delete_log_files_before(first_log_file);
+    }
+
+    // Applies the topic's retention settings, returns the number of deleted log files.
+    // Age and size limits apply even to log files consumers haven't read yet.
+    pub fn enforce_retention(&mut self) -> Result<u64> {
+        let settings = self.settings()?;
+        let mut first_log_file = self.first_log_file;
+
+        // Log files inside the minimum retention are kept whatever the other limits say
+        if let Some(retention_ms) = settings.retention_ms {
+            while first_log_file < self.curr_log_file && self.is_older_than(&first_log_file, retention_ms)? && self.is_past_min_retention(&first_log_file, settings.min_retention_ms)? {
+                first_log_file += 1;
+            }
+        }
+
+        if let Some(retention_bytes) = settings.retention_bytes {
+            let mut total_bytes:u64 = 0;
+            for log_file in first_log_file..=self.curr_log_file {
+                total_bytes += get_topic_file(&self.name, &log_file)?.len()?;
+            }
+            while first_log_file < self.curr_log_file && total_bytes > retention_bytes && self.is_past_min_retention(&first_log_file, settings.min_retention_ms)? {
+                total_bytes -= get_topic_file(&self.name, &first_log_file)?.len()?;
+                first_log_file += 1;
+            }
+        }
+
+        // Without a minimum retention, consumed log files are only deleted by cleanup
+        if settings.min_retention_ms.is_some() {
+            first_log_file = first_log_file.max(self.consumed_log_file(settings.min_retention_ms)?);
+        }
+
+        return self.delete_log_files_before(first_log_file);
+    }
+
+    // Keeps the newest event per key in the closed log files
+    pub fn compact(&self) -> Result<Compaction> {
+        let settings = self.settings()?;
+        return compact_topic(self, settings.tombstone_retention_ms.unwrap_or(DEFAULT_TOMBSTONE_RETENTION_MS));
+    }
+
+    // First log file that a consumer still needs or that is inside the minimum retention
+    fn consumed_log_file(&self, min_retention_ms: Option<u64>) -> Result<u64> {
+        if !consumers_exists() {
+            return Ok(self.first_log_file);
+        }
+        let oldest_active_log_file = get_oldest_active_log_file(&self.name)?.unwrap_or(self.first_log_file);
+        let mut log_file = self.first_log_file;
+        while log_file < oldest_active_log_file.min(self.curr_log_file) {
+            if !self.is_past_min_retention(&log_file, min_retention_ms)? {
+                break;
+            }
+            log_file += 1;
+        }
+        return Ok(log_file);
+    }
+
+    fn is_past_min_retention(&self, log_file: &u64, min_retention_ms: Option<u64>) -> Result<bool> {
+        match min_retention_ms {
+            Some(min_retention_ms) => return self.is_older_than(log_file, min_retention_ms),
+            None => return Ok(true),
+        }
+    }
+
+    // Whether the newest event of the log file is older than the given age. Log
+    // files without events have nothing to keep.
+    fn is_older_than(&self, log_file: &u64, age_ms: u64) -> Result<bool> {
+        let last_timestamp = get_last_timestamp(&self.name, log_file)?;
+        return Ok(last_timestamp.is_none_or(|timestamp| now().saturating_sub(timestamp) > age_ms));
+    }
+
+    fn delete_log_files_before(&mut self, first_log_file: u64) -> Result<u64> {
+        if first_log_file <= self.first_log_file {
+            return Ok(0);
+        }
+        let _lock = lock_topic(&self.name, true)?;
+        let deleted = first_log_file - self.first_log_file;
+        self.first_log_file = first_log_file;
+        update_first_log_file_in_config(self)?;
         delete_old_logs(&self.first_log_file, &self.name)?;
-        return Ok(());
+        return Ok(deleted);
     }
 }
 
@@ -68,17 +216,228 @@ impl Display for Topic {
     }
 }
 
+pub struct TopicStats {
+    pub topic: Topic,
+    pub messages: u64,
+    pub first_offset: u64,
+    pub next_offset: u64,
+    pub raw_bytes: u64,
+    pub disk_bytes: u64,
+}
+
+impl Display for TopicStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{{ \"topic\": {}, \"messages\": {}, \"first_offset\": {}, \"next_offset\": {}, \"raw_bytes\": {}, \"disk_bytes\": {} }}", self.topic, self.messages, self.first_offset, self.next_offset, self.raw_bytes, self.disk_bytes);
+    }
+}
+
 trait Validate {
-    fn validate(name: &String) -> bool;
+    fn validate(name: &str) -> bool;
+    fn loadable(name: &str) -> bool;
 }
 impl Validate for Topic {
-    fn validate(name: &String) -> bool {
-        if name.contains(" ") {
+    // Names end up in paths & JSON, so only letters, digits, '.', '_' and '-' are allowed
+    fn validate(name: &str) -> bool {
+        if name.is_empty() || name.len() > 255 || name == "." || name == ".." {
             return false;
-        } 
-        else if name.as_bytes().len() > i64::MAX as usize {
+        }
+        return name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+    }
+
+    // Topics created before names were restricted keep working, as long as their
+    // name can't reach outside of the logs directory
+    fn loadable(name: &str) -> bool {
+        if Topic::validate(name) {
+            return true;
+        }
+        if name.contains(['\\', '\0']) {
             return false;
         }
-        return true;
+        return name.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    }
+}
+
+// Topics a producer or consumer token may use, stored in place of its topic: one
+// topic, a comma separated set of topics or every topic starting with a prefix,
+// written as prefix*
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopicGrant {
+    Topic(String),
+    Set(Vec<String>),
+    Prefix(String),
+}
+
+impl TopicGrant {
+    pub fn parse(grant: &str) -> Result<Self> {
+        let grant = grant.to_lowercase();
+        // An existing topic whose name predates grants, e.g. one with a comma
+        if !Topic::validate(&grant) && Topic::loadable(&grant) && topic_exists(&grant) {
+            return Ok(TopicGrant::Topic(grant));
+        }
+        if let Some(prefix) = grant.strip_suffix('*') {
+            // The prefix itself doesn't need to be a valid name, only a start of one
+            if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-') {
+                return Err(InvalidInput { reason: "Invalid topic prefix.".to_string() }.into());
+            }
+            return Ok(TopicGrant::Prefix(prefix.to_owned()));
+        }
+        let mut topics:Vec<String> = Vec::new();
+        for topic in grant.split(',') {
+            let existing = Topic::loadable(topic) && topic_exists(topic);
+            if !Topic::validate(topic) && !existing {
+                return Err(InvalidInput { reason: "Invalid topic name.".to_string() }.into());
+            }
+            if !topics.iter().any(|granted| granted == topic) {
+                topics.push(topic.to_owned());
+            }
+        }
+        if topics.len() == 1 {
+            return Ok(TopicGrant::Topic(topics.remove(0)));
+        }
+        return Ok(TopicGrant::Set(topics));
+    }
+
+    pub fn allows(&self, topic: &str) -> bool {
+        match self {
+            TopicGrant::Topic(granted) => return granted == topic,
+            TopicGrant::Set(granted) => return granted.iter().any(|granted| granted == topic),
+            TopicGrant::Prefix(prefix) => return topic.starts_with(prefix.as_str()),
+        }
+    }
+
+    // The topic of a single topic grant, requests may leave it out
+    pub fn single(&self) -> Option<&str> {
+        match self {
+            TopicGrant::Topic(topic) => return Some(topic),
+            _ => return None,
+        }
+    }
+
+    // Granted topics that have to exist, a prefix also covers topics created later
+    pub fn topics(&self) -> Vec<&str> {
+        match self {
+            TopicGrant::Topic(topic) => return vec![topic.as_str()],
+            TopicGrant::Set(topics) => return topics.iter().map(|topic| topic.as_str()).collect(),
+            TopicGrant::Prefix(_) => return Vec::new(),
+        }
+    }
+
+    // The topic of a request, which has to be granted & exist. Requests without a
+    // topic only work for single topic grants.
+    pub fn resolve(&self, topic: Option<&str>) -> Result<String> {
+        let topic = match (topic, self.single()) {
+            (Some(topic), _) => topic.to_lowercase(),
+            (None, Some(topic)) => topic.to_owned(),
+            (None, None) => return Err(InvalidInput { reason: "This token is granted several topics, name the topic of the request.".to_string() }.into()),
+        };
+        if !self.allows(&topic) {
+            return Err(TopicForbidden { topic }.into());
+        }
+        if !topic_exists(&topic) {
+            return Err(UnknownTopic { topic }.into());
+        }
+        return Ok(topic);
+    }
+}
+
+impl Display for TopicGrant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopicGrant::Topic(topic) => return write!(f, "{}", topic),
+            TopicGrant::Set(topics) => return write!(f, "{}", topics.join(",")),
+            TopicGrant::Prefix(prefix) => return write!(f, "{}*", prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::storage::{test_storage, storage};
+    use crate::configs::topics::write;
+    use crate::configs::errors::TopicExists;
+    use super::super::event::Event;
+
+    #[test]
+    fn parses_topic_grants() {
+        let _storage = test_storage();
+        assert_eq!(TopicGrant::parse("Orders").unwrap(), TopicGrant::Topic("orders".to_string()));
+        assert_eq!(TopicGrant::parse("orders,payments,orders").unwrap(), TopicGrant::Set(vec!["orders".to_string(), "payments".to_string()]));
+        assert_eq!(TopicGrant::parse("orders.*").unwrap(), TopicGrant::Prefix("orders.".to_string()));
+        assert_eq!(TopicGrant::parse("*").unwrap(), TopicGrant::Prefix(String::new()));
+        for grant in ["", "orders,", "../orders", "orders/*", "a*b*"] {
+            assert!(TopicGrant::parse(grant).is_err(), "{}", grant);
+        }
+    }
+
+    #[test]
+    fn checks_topic_grants() {
+        let _storage = test_storage();
+        let set = TopicGrant::parse("orders,payments").unwrap();
+        assert!(set.allows("payments"));
+        assert!(!set.allows("refunds"));
+        assert_eq!(set.single(), None);
+        assert_eq!(set.topics(), vec!["orders", "payments"]);
+
+        let prefix = TopicGrant::parse("orders.*").unwrap();
+        assert!(prefix.allows("orders.eu"));
+        assert!(!prefix.allows("orders"));
+        assert!(prefix.topics().is_empty());
+
+        assert_eq!(TopicGrant::parse("orders").unwrap().single(), Some("orders"));
+    }
+
+    #[test]
+    fn creates_a_topic_once() {
+        let _storage = test_storage();
+        let creators:Vec<_> = (0..8).map(|_| std::thread::spawn(|| Topic::new("created".to_string()))).collect();
+        let results:Vec<_> = creators.into_iter().map(|creator| creator.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        for result in results.iter().filter(|result| result.is_err()) {
+            assert!(result.as_ref().err().unwrap().is::<TopicExists>());
+        }
+        assert_eq!(crate::configs::topics::get_topics().unwrap().iter().filter(|topic| topic.name == "created").count(), 1);
+    }
+
+    #[test]
+    fn adds_up_sizes_of_appended_records() {
+        let _storage = test_storage();
+        let plain = Topic::new("sized-plain".to_string()).unwrap();
+        let compressed = Topic::new("sized-zstd".to_string()).unwrap();
+        compressed.configure("compression", "zstd").unwrap();
+        for round in 0..3 {
+            for _ in 0..5 {
+                let content = format!("round {} ", round).repeat(50).into_bytes();
+                write(&plain.name, &Event::new(content.clone(), None, Vec::new()), false).unwrap();
+                write(&compressed.name, &Event::new(content, None, Vec::new()), false).unwrap();
+            }
+            let (raw_bytes, disk_bytes) = compressed.sizes().unwrap();
+            assert_eq!(raw_bytes, plain.sizes().unwrap().0);
+            assert!(disk_bytes < raw_bytes);
+        }
+        // Without its size index the log file is added up from the start again
+        crate::configs::indexes::delete_index(&compressed.name, &0).unwrap();
+        assert_eq!(compressed.sizes().unwrap().0, plain.sizes().unwrap().0);
+    }
+
+    #[test]
+    fn retention_deletes_log_files_on_every_pass() {
+        let _storage = test_storage();
+        let topic = Topic::new("retained".to_string()).unwrap();
+        topic.configure("segment_bytes", "500").unwrap();
+        topic.configure("retention_bytes", "1000").unwrap();
+        for _ in 0..2 {
+            for _ in 0..30 {
+                write(&topic.name, &Event::new(vec![0u8; 100], None, Vec::new()), false).unwrap();
+            }
+            let mut topic = Topic::hydrate(&topic.name).unwrap();
+            let first_log_file = topic.first_log_file;
+            assert!(topic.enforce_retention().unwrap() > 0);
+            assert!(topic.first_log_file > first_log_file);
+            for log_file in 0..=topic.curr_log_file + 1 {
+                let exists = storage().exists(&format!("logs/{}/{}", topic.name, log_file));
+                assert_eq!(exists, log_file >= topic.first_log_file, "log file {}", log_file);
+            }
+        }
     }
 }